@@ -0,0 +1,516 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use anyhow::Result;
+use indexmap::IndexMap;
+
+use crate::{
+    insert_entry,
+    table::{Slot, Table},
+    EntryKind, GlobEntry, IncludeFailure, Lexer, Operation, Origin, ParseError, ParseErrorKind,
+    ParseOptions, ParseReport, StrLines, SysctlConfig, SysctlConfigValue,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SysctlConfigValueRef<'a> {
+    String(Cow<'a, str>),
+    SysctlConfig(SysctlConfigRef<'a>),
+}
+
+/// A `SysctlConfig` whose keys and values borrow from the parsed text.
+///
+/// Only key components that need rewriting (a `/` standing for a dot in `a.eth0/100.b`) are
+/// allocated. Use `to_owned` to get a `SysctlConfig` with the same content.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SysctlConfigRef<'a> {
+    entries: IndexMap<Cow<'a, str>, SysctlConfigValueRef<'a>>,
+    origins: HashMap<Cow<'a, str>, Origin>,
+    comments: HashMap<Cow<'a, str>, Cow<'a, str>>,
+    globs: Vec<(Vec<Cow<'a, str>>, Cow<'a, str>, Origin)>,
+    exclusions: Vec<Vec<Cow<'a, str>>>,
+}
+
+impl<'a> SysctlConfigRef<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&SysctlConfigValueRef<'a>> {
+        self.entries.get(key)
+    }
+
+    /// Returns the inline comment of the value at `key`, if any.
+    pub fn comment(&self, key: &str) -> Option<&str> {
+        self.comments.get(key).map(AsRef::as_ref)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn keys(&self) -> indexmap::map::Keys<'_, Cow<'a, str>, SysctlConfigValueRef<'a>> {
+        self.entries.keys()
+    }
+
+    pub fn iter(&self) -> indexmap::map::Iter<'_, Cow<'a, str>, SysctlConfigValueRef<'a>> {
+        self.entries.iter()
+    }
+
+    pub fn to_owned(&self) -> SysctlConfig {
+        let mut config = SysctlConfig::new();
+        for (key, value) in self.entries.iter() {
+            let value = match value {
+                SysctlConfigValueRef::String(v) => {
+                    config
+                        .origins
+                        .insert(key.to_string(), self.origins[key].clone());
+                    if let Some(comment) = self.comments.get(key) {
+                        config
+                            .extras
+                            .comments
+                            .insert(key.to_string(), comment.to_string());
+                    }
+                    SysctlConfigValue::String(v.to_string())
+                }
+                SysctlConfigValueRef::SysctlConfig(m) => {
+                    SysctlConfigValue::SysctlConfig(m.to_owned())
+                }
+            };
+            config.entries.insert(key.to_string(), value);
+        }
+        config.extras.globs = self
+            .globs
+            .iter()
+            .map(|(keys, value, origin)| GlobEntry {
+                key_path: keys.iter().map(|k| k.to_string()).collect(),
+                value: value.to_string(),
+                origin: origin.clone(),
+            })
+            .collect();
+        config.extras.exclusions = self
+            .exclusions
+            .iter()
+            .map(|keys| keys.iter().map(|k| k.to_string()).collect())
+            .collect();
+        config
+    }
+}
+
+impl<'a> Table<'a> for SysctlConfigRef<'a> {
+    fn slot(&self, key: &str) -> Option<Slot<'_, Self>> {
+        match self.entries.get(key)? {
+            SysctlConfigValueRef::String(v) => Some(Slot::Leaf(v, &self.origins[key])),
+            SysctlConfigValueRef::SysctlConfig(m) => Some(Slot::Table(m)),
+        }
+    }
+
+    fn slots(&self) -> Box<dyn Iterator<Item = (&str, Slot<'_, Self>)> + '_> {
+        Box::new(self.entries.iter().map(|(key, value)| {
+            let slot = match value {
+                SysctlConfigValueRef::String(v) => Slot::Leaf(v, &self.origins[key]),
+                SysctlConfigValueRef::SysctlConfig(m) => Slot::Table(m),
+            };
+            (key.as_ref(), slot)
+        }))
+    }
+
+    fn table_mut(&mut self, key: Cow<'a, str>) -> &mut Self {
+        if let Some(SysctlConfigValueRef::String(_)) = self.entries.get(&key) {
+            self.origins.remove(&key);
+            self.comments.remove(&key);
+            self.entries.insert(
+                key.clone(),
+                SysctlConfigValueRef::SysctlConfig(SysctlConfigRef::new()),
+            );
+        }
+        match self
+            .entries
+            .entry(key)
+            .or_insert_with(|| SysctlConfigValueRef::SysctlConfig(SysctlConfigRef::new()))
+        {
+            SysctlConfigValueRef::SysctlConfig(m) => m,
+            SysctlConfigValueRef::String(_) => unreachable!(),
+        }
+    }
+
+    fn existing_table_mut(&mut self, key: &str) -> Option<&mut Self> {
+        match self.entries.get_mut(key)? {
+            SysctlConfigValueRef::SysctlConfig(m) => Some(m),
+            SysctlConfigValueRef::String(_) => None,
+        }
+    }
+
+    fn set_leaf(
+        &mut self,
+        key: Cow<'a, str>,
+        value: Cow<'a, str>,
+        origin: Origin,
+        comment: Option<Cow<'a, str>>,
+    ) -> Option<(Cow<'a, str>, Origin)> {
+        match comment {
+            Some(comment) => self.comments.insert(key.clone(), comment),
+            None => self.comments.remove(&key),
+        };
+        let old_origin = self.origins.insert(key.clone(), origin);
+        let old_value = self
+            .entries
+            .insert(key, SysctlConfigValueRef::String(value));
+        match (old_value, old_origin) {
+            (Some(SysctlConfigValueRef::String(v)), Some(o)) => Some((v, o)),
+            _ => None,
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        self.origins.remove(key);
+        self.comments.remove(key);
+        self.entries.shift_remove(key).is_some()
+    }
+
+    fn push_glob(&mut self, key_path: Vec<Cow<'a, str>>, value: Cow<'a, str>, origin: Origin) {
+        self.globs.push((key_path, value, origin));
+    }
+
+    fn push_exclusion(&mut self, key_path: Vec<Cow<'a, str>>) {
+        self.exclusions.push(key_path);
+    }
+
+    // Operations are applied by `SysctlConfig::merge`, which a borrowed config can't take part
+    // in, so `+=` and `!` lines are rejected.
+    fn operations_mut(&mut self) -> Option<&mut Vec<Operation>> {
+        None
+    }
+}
+
+pub fn parse_borrowed(s: &str) -> Result<SysctlConfigRef<'_>> {
+    ParseOptions::default().parse_borrowed(s)
+}
+
+impl ParseOptions {
+    /// Like `parse_str`, but borrows keys and values from `s` instead of copying them.
+    /// Included files can't be borrowed from, so with `includes` set an `include` line is
+    /// rejected with `ParseErrorKind::IncludeFailed`, and `+=` and `!` lines, which are kept
+    /// for a later merge, with `ParseErrorKind::UnsupportedOperator`.
+    pub fn parse_borrowed<'a>(&self, s: &'a str) -> Result<SysctlConfigRef<'a>> {
+        let mut report = ParseReport::new(SysctlConfigRef::new());
+        let mut lexer = Lexer::new(StrLines::new(s), None, self);
+        while let Some(entry) = lexer.next_entry() {
+            let result = match entry {
+                Ok(entry) if entry.kind == EntryKind::Include => Err(ParseError {
+                    path: None,
+                    line: entry.line,
+                    column: entry.column,
+                    text: entry.text.into_owned(),
+                    kind: ParseErrorKind::IncludeFailed(Box::new(IncludeFailure {
+                        path: entry.value.as_ref().into(),
+                        message: "includes are not supported by parse_borrowed".to_string(),
+                    })),
+                    include_chain: vec![],
+                }),
+                Ok(entry) => insert_entry(&mut report, entry, None, self),
+                Err(e) => Err(e.downcast::<ParseError>()?),
+            };
+            if let Err(e) = result {
+                report.errors.push(e);
+                break;
+            }
+        }
+        report.into_result()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_str, ConflictPolicy, UnresolvedVariables};
+
+    const TEST_DATA: &str = "# comment
+net.ipv4.ip_forward = 1
+net.ipv4.conf.eth0/100.rp_filter = 2
+net.ipv4.conf.*.accept_redirects = 0
+-net.ipv4.conf.lo.accept_redirects
+kernel.sysrq = 16
+";
+
+    // The table at `key` of `map`, failing the test if there is none.
+    fn table<'m, 'a>(map: &'m SysctlConfigRef<'a>, key: &str) -> &'m SysctlConfigRef<'a> {
+        match map.get(key) {
+            Some(SysctlConfigValueRef::SysctlConfig(m)) => m,
+            other => panic!("expected a table at {:?}, but got {:?}", key, other),
+        }
+    }
+
+    #[test]
+    fn ok_parse_borrowed() {
+        let map = parse_borrowed(TEST_DATA).unwrap();
+
+        let ipv4 = table(table(&map, "net"), "ipv4");
+        assert!(matches!(
+            ipv4.get("ip_forward"),
+            Some(SysctlConfigValueRef::String(Cow::Borrowed("1")))
+        ));
+        let conf = table(ipv4, "conf");
+        assert_eq!(conf.keys().collect::<Vec<_>>(), vec!["eth0.100"]);
+    }
+
+    #[test]
+    fn ok_to_owned() {
+        let borrowed = parse_borrowed(TEST_DATA).unwrap().to_owned();
+        let owned = parse_str(TEST_DATA).unwrap();
+
+        assert_eq!(
+            borrowed.keys().collect::<Vec<_>>(),
+            owned.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            borrowed.entry_origin("net.ipv4.ip_forward"),
+            owned.entry_origin("net.ipv4.ip_forward")
+        );
+        assert_eq!(borrowed.entry_origin("kernel.sysrq").unwrap().line, 6);
+        assert_eq!(borrowed.globs(), owned.globs());
+        assert_eq!(borrowed.exclusions(), owned.exclusions());
+    }
+
+    #[test]
+    fn ok_to_owned_without_slash_separator() {
+        let options = ParseOptions::new().slash_separator(false);
+        let test_data = "kern.a/b* = 1\n-/;\n-kern.a/c\n";
+        let owned = options.parse_str(test_data).unwrap();
+        assert_eq!(options.parse_borrowed(test_data).unwrap().to_owned(), owned);
+        assert_eq!(owned.globs()[0].key_path, ["kern", "a/b*"]);
+        assert_eq!(owned.exclusions(), [vec!["/;"], vec!["kern", "a/c"]]);
+    }
+
+    #[test]
+    fn ok_to_owned_with_presets() {
+        let presets = [
+            ("new", ParseOptions::new()),
+            ("strict", ParseOptions::strict()),
+            ("procps", ParseOptions::procps()),
+            ("systemd", ParseOptions::systemd()),
+            ("busybox", ParseOptions::busybox()),
+            ("freebsd", ParseOptions::freebsd()),
+        ];
+        let test_data = [
+            TEST_DATA,
+            "\u{FEFF}# comment\r\n  ; indented\r\nkernel.sysrq = 16 # raised\r\nkernel.sysrq = 1\r\n",
+            "kernel.domainname =\nkernel host name = x\n-kernel.panic\n- foobar\n",
+            "foo = 1\nfoo.bar = 2\nbaz.qux = 3\nbaz = 4\n",
+        ];
+        for (name, options) in presets {
+            for data in test_data {
+                match (options.parse_borrowed(data), options.parse_str(data)) {
+                    (Ok(borrowed), Ok(owned)) => {
+                        let borrowed = borrowed.to_owned();
+                        assert_eq!(borrowed, owned, "{} {:?}", name, data);
+                        for key in ["kernel.sysrq", "net.ipv4.ip_forward", "foo", "baz"] {
+                            assert_eq!(borrowed.entry_origin(key), owned.entry_origin(key));
+                            assert_eq!(borrowed.entry_comment(key), owned.entry_comment(key));
+                        }
+                    }
+                    (Err(borrowed), Err(owned)) => assert_eq!(
+                        borrowed.downcast_ref::<ParseError>(),
+                        owned.downcast_ref::<ParseError>(),
+                        "{} {:?}",
+                        name,
+                        data
+                    ),
+                    (borrowed, owned) => panic!(
+                        "{} {:?}: parse_borrowed gave {:?}, but parse_str gave {:?}",
+                        name, data, borrowed, owned
+                    ),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ok_conflict_policy() {
+        let options = ParseOptions::new().conflict_policy(ConflictPolicy::LastWins);
+        let map = options.parse_borrowed("foo.bar = 1\nfoo = 2\n").unwrap();
+        assert_eq!(
+            map.get("foo"),
+            Some(&SysctlConfigValueRef::String(Cow::Borrowed("2")))
+        );
+
+        let options = ParseOptions::new().conflict_policy(ConflictPolicy::FirstWins);
+        let map = options.parse_borrowed("foo = 1\nfoo.bar = 2\n").unwrap();
+        assert_eq!(
+            map.get("foo"),
+            Some(&SysctlConfigValueRef::String(Cow::Borrowed("1")))
+        );
+    }
+
+    #[test]
+    fn ng_parse_borrowed() {
+        let err = parse_borrowed("foo = 1\nbar\n").unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::MissingDelimiter);
+        assert_eq!(err.line, 2);
+
+        let err = parse_borrowed("foo = 1\nfoo.bar = 2\n").unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert!(matches!(
+            err.kind,
+            ParseErrorKind::LeafBranchConflict { .. }
+        ));
+        assert_eq!(err.line, 2);
+
+        let err = ParseOptions::new()
+            .deny_duplicates(true)
+            .parse_borrowed("foo = 1\nfoo = 2\n")
+            .unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert!(matches!(err.kind, ParseErrorKind::DuplicateKey { .. }));
+
+        // Conflicts are reported exactly as by the owned parser.
+        let options = ParseOptions::strict().line_continuation(true);
+        for test_data in [
+            "foo = 1\n  foo.bar = 2\n",
+            "foo.bar.baz = 1\nfoo.qux = 2\n foo = 3\n",
+            "foo = 1\nbar = 2\nfoo \\\n = 3\n",
+            "foo.bar = 1\n-foo = 2\nfoo.bar.baz = \\\n 3\n",
+        ] {
+            let expected = options.parse_str(test_data).unwrap_err();
+            let err = options.parse_borrowed(test_data).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<ParseError>().unwrap(),
+                expected.downcast_ref::<ParseError>().unwrap(),
+                "{:?}",
+                test_data
+            );
+        }
+    }
+
+    #[test]
+    fn ok_quoted_values() {
+        let options = ParseOptions::new().quoted_values(true);
+        let map = options
+            .parse_borrowed("foo = \"bar baz\"\nqux = \"a\\tb\"\n")
+            .unwrap();
+        assert_eq!(
+            map.get("foo"),
+            Some(&SysctlConfigValueRef::String(Cow::Borrowed("bar baz")))
+        );
+        assert_eq!(
+            map.get("qux"),
+            Some(&SysctlConfigValueRef::String(Cow::Owned(
+                "a\tb".to_string()
+            )))
+        );
+
+        let err = options.parse_borrowed("foo = \"bar\n").unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::UnterminatedQuote);
+    }
+
+    #[test]
+    fn ok_line_continuation() {
+        let test_data = "foo = 1 \\\n  2\nbar = 3\n";
+        let options = ParseOptions::new().line_continuation(true);
+        let map = options.parse_borrowed(test_data).unwrap();
+        assert_eq!(
+            map.get("foo"),
+            Some(&SysctlConfigValueRef::String(Cow::Owned(
+                "1   2".to_string()
+            )))
+        );
+        assert_eq!(
+            map.get("bar"),
+            Some(&SysctlConfigValueRef::String(Cow::Borrowed("3")))
+        );
+        assert_eq!(map.to_owned(), options.parse_str(test_data).unwrap());
+
+        let err = options
+            .parse_borrowed("foo = 1\nbar \\\n baz = 2\n")
+            .unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!((err.line, err.column), (2, 4));
+    }
+
+    #[test]
+    fn ok_inline_comments() {
+        let options = ParseOptions::new().inline_comments(true);
+        let map = options
+            .parse_borrowed("foo = 10  # tuned\nbar = 1\n")
+            .unwrap();
+        assert_eq!(
+            map.get("foo"),
+            Some(&SysctlConfigValueRef::String(Cow::Borrowed("10")))
+        );
+        assert_eq!(map.comment("foo"), Some("tuned"));
+        assert_eq!(map.comment("bar"), None);
+        assert_eq!(map.to_owned().entry_comment("foo"), Some("tuned"));
+    }
+
+    #[test]
+    fn ng_include() {
+        let err = ParseOptions::new()
+            .includes(true)
+            .parse_borrowed("foo = 1\ninclude other.conf\n")
+            .unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert!(matches!(err.kind, ParseErrorKind::IncludeFailed(_)));
+        assert_eq!(err.line, 2);
+    }
+
+    #[test]
+    fn ng_operators() {
+        let options = ParseOptions::new().operators(true).line_continuation(true);
+        for test_data in [
+            "foo = 1\nfoo += 2\n",
+            "foo = 1\n  !foo\n",
+            "foo = 1\nfoo += \\\n  2\n",
+        ] {
+            let err = options.parse_borrowed(test_data).unwrap_err();
+            let err = err.downcast_ref::<ParseError>().unwrap();
+            assert_eq!(
+                err.kind,
+                ParseErrorKind::UnsupportedOperator,
+                "{:?}",
+                test_data
+            );
+            assert_eq!(err.line, 2);
+        }
+    }
+
+    #[test]
+    fn ok_variables() {
+        let options = ParseOptions::new().variables(HashMap::from([(
+            "LOG_DIR".to_string(),
+            "/var/log".to_string(),
+        )]));
+        let map = options
+            .parse_borrowed("foo = ${LOG_DIR}/app.log\nbar = 1\n")
+            .unwrap();
+        assert_eq!(
+            map.get("foo"),
+            Some(&SysctlConfigValueRef::String(Cow::Owned(
+                "/var/log/app.log".to_string()
+            )))
+        );
+        assert_eq!(
+            map.get("bar"),
+            Some(&SysctlConfigValueRef::String(Cow::Borrowed("1")))
+        );
+
+        let err = options
+            .parse_borrowed("foo = ${A}\nbar = ${B}\n")
+            .unwrap_err();
+        let err = err.downcast_ref::<UnresolvedVariables>().unwrap();
+        assert_eq!(
+            err.variables
+                .iter()
+                .map(|v| (v.name.as_str(), v.origin.line))
+                .collect::<Vec<_>>(),
+            vec![("A", 1), ("B", 2)]
+        );
+    }
+}
@@ -0,0 +1,333 @@
+use anyhow::{Error, Result};
+
+use crate::{entry_of_line, join_key, join_key_with, EntryKind, ParseOptions, SysctlConfig};
+
+/// The sysctl file syntax of a particular tool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    /// procps `sysctl -p`, see `ParseOptions::procps`.
+    Procps,
+    /// systemd-sysctl, see `ParseOptions::systemd`.
+    Systemd,
+    /// busybox `sysctl -p`, see `ParseOptions::busybox`.
+    Busybox,
+    /// FreeBSD `/etc/sysctl.conf`, see `ParseOptions::freebsd`.
+    FreeBsd,
+}
+
+impl Dialect {
+    /// The parser settings that accept this dialect.
+    pub fn options(self) -> ParseOptions {
+        match self {
+            Dialect::Procps => ParseOptions::procps(),
+            Dialect::Systemd => ParseOptions::systemd(),
+            Dialect::Busybox => ParseOptions::busybox(),
+            Dialect::FreeBsd => ParseOptions::freebsd(),
+        }
+    }
+
+    /// Formats `config` as a file of this dialect: one line per value in insertion order,
+    /// followed by the globs and then the exclusions. Fails if anything in `config` would not
+    /// read back the same with `options`, e.g. an empty value outside systemd or a glob in
+    /// FreeBSD, or pending `+=` and `!` operations, which no dialect has.
+    pub fn serialize(self, config: &SysctlConfig) -> Result<String> {
+        if let Some(op) = config.operations().first() {
+            return Err(Error::msg(format!(
+                "cannot write operation as {:?}: {}",
+                self,
+                join_key(&op.key_path)
+            )));
+        }
+        let options = self.options();
+        let mut text = String::new();
+        for (keys, value) in config.leaves() {
+            let line = self.line(&join_key_with(&keys, &options), &value);
+            match entry_of_line(&line, None, 1, &options) {
+                Ok(Some(entry))
+                    if entry.kind == EntryKind::Assign
+                        && entry.key_path == keys
+                        && entry.value == value => {}
+                _ => {
+                    return Err(Error::msg(format!(
+                        "cannot write entry as {:?}: {:?}",
+                        self, line
+                    )))
+                }
+            }
+            text.push_str(&line);
+            text.push('\n');
+        }
+
+        for glob in config.globs() {
+            let line = self.line(&join_key_with(&glob.key_path, &options), &glob.value);
+            match entry_of_line(&line, None, 1, &options) {
+                Ok(Some(entry))
+                    if options.globs
+                        && entry.key_path == glob.key_path
+                        && entry.value == glob.value => {}
+                _ => {
+                    return Err(Error::msg(format!(
+                        "cannot write glob as {:?}: {:?}",
+                        self, line
+                    )))
+                }
+            }
+            text.push_str(&line);
+            text.push('\n');
+        }
+
+        for keys in config.exclusions() {
+            let key = join_key_with(keys, &options);
+            if !options.ignore_error_prefix {
+                return Err(Error::msg(format!(
+                    "cannot write exclusion as {:?}: {:?}",
+                    self, key
+                )));
+            }
+            text.push_str(&format!("-{}\n", key));
+        }
+        Ok(text)
+    }
+
+    fn line(self, key: &str, value: &str) -> String {
+        match self {
+            Dialect::Procps | Dialect::Systemd => format!("{} = {}", key, value),
+            Dialect::Busybox | Dialect::FreeBsd => format!("{}={}", key, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ParseError, ParseErrorKind};
+
+    const DIALECTS: [Dialect; 4] = [
+        Dialect::Procps,
+        Dialect::Systemd,
+        Dialect::Busybox,
+        Dialect::FreeBsd,
+    ];
+
+    // Each input with the expected outcome for procps, systemd, busybox and FreeBSD: the
+    // flattened config (see `flatten`), or the error for its first invalid line.
+    const CORPUS: &[(&str, [Result<&str, ParseErrorKind>; 4])] = &[
+        (
+            "kernel.sysrq = 1\n",
+            [
+                Ok("kernel.sysrq=1"),
+                Ok("kernel.sysrq=1"),
+                Ok("kernel.sysrq=1"),
+                Ok("kernel.sysrq=1"),
+            ],
+        ),
+        (
+            "kernel.sysrq=1\r\n",
+            [
+                Ok("kernel.sysrq=1"),
+                Ok("kernel.sysrq=1"),
+                Ok("kernel.sysrq=1"),
+                Ok("kernel.sysrq=1"),
+            ],
+        ),
+        (
+            "  # indented\n\t\nkernel.sysrq = 1\n",
+            [
+                Ok("kernel.sysrq=1"),
+                Ok("kernel.sysrq=1"),
+                Ok("kernel.sysrq=1"),
+                Ok("kernel.sysrq=1"),
+            ],
+        ),
+        (
+            "; semicolon\n",
+            [
+                Ok(""),
+                Ok(""),
+                Ok(""),
+                Err(ParseErrorKind::MissingDelimiter),
+            ],
+        ),
+        (
+            "-kernel.sysrq = 1\n",
+            [
+                Ok("kernel.sysrq=1"),
+                Ok("kernel.sysrq=1"),
+                Ok("-kernel.sysrq=1"),
+                Ok("-kernel.sysrq=1"),
+            ],
+        ),
+        (
+            "-kernel.sysrq\n",
+            [
+                Ok("-kernel.sysrq"),
+                Ok("-kernel.sysrq"),
+                Err(ParseErrorKind::MissingDelimiter),
+                Err(ParseErrorKind::MissingDelimiter),
+            ],
+        ),
+        (
+            "kernel.domainname =\n",
+            [
+                Err(ParseErrorKind::EmptyValue),
+                Ok("kernel.domainname="),
+                Err(ParseErrorKind::EmptyValue),
+                Err(ParseErrorKind::EmptyValue),
+            ],
+        ),
+        (
+            "net/ipv4/ip_forward = 1\n",
+            [
+                Ok("net.ipv4.ip_forward=1"),
+                Ok("net.ipv4.ip_forward=1"),
+                Ok("net.ipv4.ip_forward=1"),
+                Ok("net/ipv4/ip_forward=1"),
+            ],
+        ),
+        (
+            "net.ipv4.conf.*.rp_filter = 2\n",
+            [
+                Ok("glob net.ipv4.conf.*.rp_filter=2"),
+                Ok("glob net.ipv4.conf.*.rp_filter=2"),
+                Ok("net.ipv4.conf.*.rp_filter=2"),
+                Ok("net.ipv4.conf.*.rp_filter=2"),
+            ],
+        ),
+        (
+            "kern.maxfiles=65536 # raised\n",
+            [
+                Ok("kern.maxfiles=65536 # raised"),
+                Ok("kern.maxfiles=65536 # raised"),
+                Ok("kern.maxfiles=65536 # raised"),
+                Ok("kern.maxfiles=65536"),
+            ],
+        ),
+        (
+            "foo bar = 1\n",
+            [
+                Err(ParseErrorKind::WhitespaceInKey),
+                Err(ParseErrorKind::WhitespaceInKey),
+                Ok("foo bar=1"),
+                Err(ParseErrorKind::WhitespaceInKey),
+            ],
+        ),
+        (
+            "kernel = 1\nkernel.sysrq = 1\n",
+            [
+                Ok("kernel.sysrq=1"),
+                Ok("kernel.sysrq=1"),
+                Ok("kernel.sysrq=1"),
+                Ok("kernel.sysrq=1"),
+            ],
+        ),
+    ];
+
+    // One line per value, glob and exclusion, so that expectations stay short.
+    fn flatten(config: &SysctlConfig) -> String {
+        let mut lines = config
+            .leaves()
+            .into_iter()
+            .map(|(keys, value)| format!("{}={}", join_key(&keys), value))
+            .collect::<Vec<String>>();
+        lines.extend(
+            config
+                .globs()
+                .iter()
+                .map(|g| format!("glob {}={}", join_key(&g.key_path), g.value)),
+        );
+        lines.extend(
+            config
+                .exclusions()
+                .iter()
+                .map(|keys| format!("-{}", join_key(keys))),
+        );
+        lines.join("\n")
+    }
+
+    #[test]
+    fn ok_corpus() {
+        for (test_data, expected) in CORPUS {
+            for (dialect, expected) in DIALECTS.iter().zip(expected) {
+                let result = dialect.options().parse_str(test_data);
+                match (result, expected) {
+                    (Ok(config), Ok(expected)) => assert_eq!(
+                        flatten(&config),
+                        *expected,
+                        "{:?}: {:?}",
+                        dialect,
+                        test_data
+                    ),
+                    (Err(err), Err(kind)) => {
+                        let err = err.downcast_ref::<ParseError>().unwrap();
+                        assert_eq!(err.kind, *kind, "{:?}: {:?}", dialect, test_data);
+                    }
+                    (result, _) => panic!(
+                        "{:?}: {:?}: expected {:?}, but got {:?}",
+                        dialect,
+                        test_data,
+                        expected,
+                        result.map(|c| flatten(&c))
+                    ),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ok_serialize_round_trip() {
+        for (test_data, expected) in CORPUS {
+            for (dialect, expected) in DIALECTS.iter().zip(expected) {
+                if expected.is_err() {
+                    continue;
+                }
+                let config = dialect.options().parse_str(test_data).unwrap();
+                let text = dialect.serialize(&config).unwrap();
+                assert_eq!(
+                    dialect.options().parse_str(&text).unwrap(),
+                    config,
+                    "{:?}: {:?}",
+                    dialect,
+                    text
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ok_serialize() {
+        let config = ParseOptions::new()
+            .parse_str("kernel.sysrq = 1\nnet/ipv4/conf/eth0.100/rp_filter = 2\n")
+            .unwrap();
+        assert_eq!(
+            Dialect::Systemd.serialize(&config).unwrap(),
+            "kernel.sysrq = 1\nnet/ipv4/conf/eth0.100/rp_filter = 2\n"
+        );
+        assert!(Dialect::FreeBsd.serialize(&config).is_err());
+
+        let config = ParseOptions::new().parse_str("kernel.sysrq = 1\n").unwrap();
+        assert_eq!(
+            Dialect::FreeBsd.serialize(&config).unwrap(),
+            "kernel.sysrq=1\n"
+        );
+    }
+
+    #[test]
+    fn ng_serialize() {
+        let config = ParseOptions::systemd()
+            .parse_str("kernel.domainname =\n")
+            .unwrap();
+        assert!(Dialect::Systemd.serialize(&config).is_ok());
+        assert!(Dialect::Procps.serialize(&config).is_err());
+
+        let config = ParseOptions::new()
+            .parse_str("net.ipv4.conf.*.rp_filter = 2\n-net.ipv4.conf.lo.rp_filter\n")
+            .unwrap();
+        assert!(Dialect::Procps.serialize(&config).is_ok());
+        assert!(Dialect::Busybox.serialize(&config).is_err());
+
+        let config = ParseOptions::new()
+            .parse_str("kern.hostname = a # b\n")
+            .unwrap();
+        assert!(Dialect::FreeBsd.serialize(&config).is_err());
+    }
+}
@@ -0,0 +1,632 @@
+use std::{borrow::Cow, fmt, ops::Range, path::Path};
+
+use anyhow::{Error, Result};
+
+use crate::{
+    continues, join_key_with, lex_line, section_of, split_key_with, strip_comment, value_of,
+    ContinuedLine, EntryLine, Line, ParseOptions, SysctlConfig,
+};
+
+/// A sysctl file kept byte-for-byte, including comments, blank lines, `-` prefixes and
+/// spacing, so single entries can be edited without reformatting the rest of the file.
+///
+/// Entries are found the way its `ParseOptions` read them: keys below a `[section]` header
+/// include the section, values are read without their quotes and inline comments, and an entry
+/// continued over several lines is one entry. Lines that fail to parse are kept verbatim and
+/// never matched by the editing methods; `to_config` reports them. A byte order mark is kept
+/// apart from the first line and written back as it was.
+///
+/// Two documents are equal when they hold the same text, whatever options they were parsed with.
+#[derive(Debug, Clone)]
+pub struct Document {
+    bom: bool,
+    lines: Vec<DocumentLine>,
+    options: ParseOptions,
+}
+
+impl PartialEq for Document {
+    fn eq(&self, other: &Self) -> bool {
+        self.bom == other.bom && self.lines == other.lines
+    }
+}
+
+impl Eq for Document {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DocumentLine {
+    text: String,
+    eol: String,
+}
+
+// A line as the parser reads it: one physical line, or several joined by trailing backslashes
+// with `ParseOptions::line_continuation`.
+struct LogicalLine<'d> {
+    // The physical lines it spans.
+    lines: Range<usize>,
+    text: Cow<'d, str>,
+    is_header: bool,
+    // The key prefix in effect after this line, `None` after an invalid `[section]` header.
+    section: Option<Vec<String>>,
+    // The line of the `[section]` header that set it.
+    header: Option<usize>,
+}
+
+impl Document {
+    pub fn parse(s: &str) -> Self {
+        Self::parse_with(s, ParseOptions::default())
+    }
+
+    /// Like `parse`, but entries are read with `options`, e.g. `ParseOptions::systemd` to find
+    /// and write entries with empty values.
+    pub fn parse_with(s: &str, options: ParseOptions) -> Self {
+        let (s, bom) = match s.strip_prefix('\u{FEFF}') {
+            Some(s) => (s, true),
+            None => (s, false),
+        };
+        let lines = s
+            .split_inclusive('\n')
+            .map(|l| {
+                let text = l.trim_end_matches('\n').trim_end_matches('\r');
+                DocumentLine {
+                    text: text.to_string(),
+                    eol: l[text.len()..].to_string(),
+                }
+            })
+            .collect();
+        Self {
+            bom,
+            lines,
+            options,
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::load_with(path, ParseOptions::default())
+    }
+
+    /// Like `load`, but entries are read with `options`. A file that is not valid UTF-8 is an
+    /// error, unless `ParseOptions::lossy_utf8` is set; then invalid bytes are replaced, and
+    /// written back as replacement characters.
+    pub fn load_with(path: impl AsRef<Path>, options: ParseOptions) -> Result<Self> {
+        let text = match String::from_utf8(std::fs::read(path)?) {
+            Ok(text) => text,
+            Err(e) if options.lossy_utf8 => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self::parse_with(&text, options))
+    }
+
+    pub fn to_config(&self) -> Result<SysctlConfig> {
+        self.options.parse_str(&self.to_string())
+    }
+
+    /// Returns the effective (last assigned) value of `key`, without quotes or inline comment.
+    pub fn get(&self, key: &str) -> Option<Cow<'_, str>> {
+        let line = self.find(key).pop()?;
+        match line.text {
+            Cow::Borrowed(text) => Some(assignment(text, &self.options)?.1),
+            Cow::Owned(text) => Some(Cow::Owned(assignment(&text, &self.options)?.1.into_owned())),
+        }
+    }
+
+    /// Replaces the value of every assignment of `key`, keeping the surrounding spacing, quotes
+    /// and inline comment. An assignment continued over several lines is joined into one.
+    /// Appends `key = value` at the end of the document if `key` is not assigned yet.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let found = self.find(key);
+        if found.is_empty() {
+            return self.insert_entry(self.lines.len(), key, value);
+        }
+
+        let mut edits = vec![];
+        for line in found {
+            edits.push((line.lines, with_value(&line.text, value, &self.options)?));
+        }
+        for (lines, text) in edits.into_iter().rev() {
+            let eol = self.lines[lines.end - 1].eol.clone();
+            self.lines.splice(lines, [DocumentLine { text, eol }]);
+        }
+        Ok(())
+    }
+
+    /// Deletes every assignment of `key`. Returns whether anything was removed.
+    pub fn remove(&mut self, key: &str) -> bool {
+        let found = self.ranges_of(key);
+        for lines in found.iter().rev() {
+            self.lines.drain(lines.clone());
+        }
+        !found.is_empty()
+    }
+
+    /// Inserts `key = value` on the line after the last assignment of `anchor`.
+    /// Returns `false` without changing anything if `anchor` is not assigned.
+    pub fn insert_after(&mut self, anchor: &str, key: &str, value: &str) -> Result<bool> {
+        let Some(lines) = self.ranges_of(anchor).pop() else {
+            return Ok(false);
+        };
+        self.insert_entry(lines.end, key, value)?;
+        Ok(true)
+    }
+
+    /// Turns every assignment of `key` into a `#` comment. Returns whether anything changed.
+    pub fn comment_out(&mut self, key: &str) -> bool {
+        let found = self.ranges_of(key);
+        for i in found.iter().flat_map(|lines| lines.clone()) {
+            self.lines[i].text.insert_str(0, "# ");
+        }
+        !found.is_empty()
+    }
+
+    // Keys are compared by their components, so with the default options `net/ipv4/ip_forward`
+    // finds `net.ipv4.ip_forward`.
+    fn find(&self, key: &str) -> Vec<LogicalLine<'_>> {
+        let key = split_key_with(key, &self.options);
+        self.logical_lines()
+            .into_iter()
+            .filter(|line| {
+                let (Some(section), false) = (&line.section, line.is_header) else {
+                    return false;
+                };
+                let Some((entry, _)) = assignment(&line.text, &self.options) else {
+                    return false;
+                };
+                let keys = section
+                    .iter()
+                    .map(|s| Cow::Borrowed(s.as_str()))
+                    .chain(split_key_with(entry.key, &self.options));
+                keys.eq(key.iter().cloned())
+            })
+            .collect()
+    }
+
+    // The physical lines of every assignment of `key`.
+    fn ranges_of(&self, key: &str) -> Vec<Range<usize>> {
+        self.find(key).into_iter().map(|line| line.lines).collect()
+    }
+
+    fn logical_lines(&self) -> Vec<LogicalLine<'_>> {
+        let mut logical = vec![];
+        let mut section = Some(vec![]);
+        let mut header = None;
+        let mut i = 0;
+        while i < self.lines.len() {
+            let start = i;
+            let line = self.lines[i].text.as_str();
+            i += 1;
+            let is_header = match section_of(line, &self.options) {
+                Some(parsed) => {
+                    section = parsed.ok();
+                    header = Some(start);
+                    true
+                }
+                None => false,
+            };
+            let text = if !is_header && continues(line, &self.options) {
+                let mut joined = ContinuedLine::new(start, line);
+                while joined.more && i < self.lines.len() {
+                    joined.push(i, &self.lines[i].text);
+                    i += 1;
+                }
+                Cow::Owned(joined.text)
+            } else {
+                Cow::Borrowed(line)
+            };
+            logical.push(LogicalLine {
+                lines: start..i,
+                text,
+                is_header,
+                section: section.clone(),
+                header,
+            });
+        }
+        logical
+    }
+
+    // Inserts `key = value` before line `at`. Below a `[section]` header the key is written
+    // relative to the section, or, if it lies outside of it, between a `[]` header and a copy
+    // of the section header.
+    fn insert_entry(&mut self, at: usize, key: &str, value: &str) -> Result<()> {
+        let (section, header) = self
+            .logical_lines()
+            .into_iter()
+            .take_while(|line| line.lines.end <= at)
+            .last()
+            .map_or((Some(vec![]), None), |line| (line.section, line.header));
+        let keys = split_key_with(key, &self.options);
+        let mut texts = vec![];
+        match section {
+            Some(section) if section.is_empty() => {
+                texts.push(entry_text(key, value, &self.options)?)
+            }
+            Some(section)
+                if keys.len() > section.len()
+                    && keys.iter().zip(section.iter()).all(|(k, s)| k == s) =>
+            {
+                let key = join_key_with(&keys[section.len()..], &self.options);
+                texts.push(entry_text(&key, value, &self.options)?);
+            }
+            _ => {
+                texts.push("[]".to_string());
+                texts.push(entry_text(key, value, &self.options)?);
+                if let Some(header) = header.filter(|_| at < self.lines.len()) {
+                    texts.push(self.lines[header].text.clone());
+                }
+            }
+        }
+        for (i, text) in texts.into_iter().enumerate() {
+            self.insert_line(at + i, text);
+        }
+        Ok(())
+    }
+
+    fn insert_line(&mut self, at: usize, text: String) {
+        let eol = match self.lines.first() {
+            Some(l) if l.eol == "\r\n" => "\r\n",
+            _ => "\n",
+        };
+        if let Some(prev) = at.checked_sub(1).map(|i| &mut self.lines[i]) {
+            if prev.eol.is_empty() {
+                prev.eol = eol.to_string();
+            }
+        }
+        self.lines.insert(
+            at,
+            DocumentLine {
+                text,
+                eol: eol.to_string(),
+            },
+        );
+    }
+}
+
+impl fmt::Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.bom {
+            write!(f, "\u{FEFF}")?;
+        }
+        for line in self.lines.iter() {
+            write!(f, "{}{}", line.text, line.eol)?;
+        }
+        Ok(())
+    }
+}
+
+// The entry on the logical line `text` and its decoded value, if it is a valid assignment.
+fn assignment<'t>(text: &'t str, options: &ParseOptions) -> Option<(EntryLine<'t>, Cow<'t, str>)> {
+    match lex_line(text, options) {
+        Ok(Line::Entry(entry)) if !entry.append => {
+            let (value, _) = value_of(&entry, options).ok()?;
+            Some((entry, value))
+        }
+        _ => None,
+    }
+}
+
+// Whether the single line `text` is an assignment of `value`.
+fn reads_back(text: &str, value: &str, options: &ParseOptions) -> bool {
+    !text.contains(['\n', '\r'])
+        && !continues(text, options)
+        && matches!(assignment(text, options), Some((_, v)) if v == value)
+}
+
+// `value` as written on a new line: as is if that reads back, and otherwise quoted if
+// `options.quoted_values` allows it.
+fn encodings(value: &str, options: &ParseOptions) -> Vec<String> {
+    let mut encoded = vec![value.to_string()];
+    if options.quoted_values {
+        encoded.push(quoted(value, '"'));
+    }
+    encoded
+}
+
+// `value` between `quote` characters, escaped so that it decodes back to itself.
+fn quoted(value: &str, quote: char) -> String {
+    let mut quoted = String::from(quote);
+    for c in value.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\\' => quoted.push_str("\\\\"),
+            c if c == quote => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push(quote);
+    quoted
+}
+
+// The logical line `text`, an assignment, with its value replaced by `value`. The spacing and
+// inline comment around the value are kept, and so are its quotes if it has any.
+fn with_value(text: &str, value: &str, options: &ParseOptions) -> Result<String> {
+    let invalid = || Error::msg(format!("invalid value: {:?}", value));
+    let (entry, _) = assignment(text, options).ok_or_else(invalid)?;
+    let (old, _) = strip_comment(entry.value, options);
+    let range = entry.value_start..entry.value_start + old.len();
+    let encoded = match old.chars().next() {
+        Some(quote @ ('"' | '\'')) if options.quoted_values => vec![quoted(value, quote)],
+        _ => encodings(value, options),
+    };
+
+    // An empty value has no spacing after the `=` to keep; mirror the one before it.
+    let before = &text[..entry.value_start];
+    let space = old.is_empty()
+        && before.ends_with('=')
+        && before[..before.len() - 1].ends_with([' ', '\t']);
+    for encoded in encoded {
+        let mut new_text = text.to_string();
+        if space && !encoded.is_empty() {
+            new_text.replace_range(range.clone(), &format!(" {}", encoded));
+        } else {
+            new_text.replace_range(range.clone(), &encoded);
+        }
+        if reads_back(&new_text, value, options) {
+            return Ok(new_text);
+        }
+    }
+    Err(invalid())
+}
+
+// Formats a new entry line and checks that it reads back as exactly `key` and `value`.
+fn entry_text(key: &str, value: &str, options: &ParseOptions) -> Result<String> {
+    let texts = encodings(value, options)
+        .into_iter()
+        .map(|encoded| format!("{} = {}", key, encoded))
+        .collect::<Vec<String>>();
+    for text in texts.iter() {
+        if matches!(assignment(text, options), Some((entry, _)) if entry.key == key)
+            && reads_back(text, value, options)
+        {
+            return Ok(text.clone());
+        }
+    }
+    match lex_line(&texts[0], options) {
+        Err(e) => Err(Error::msg(format!(
+            "invalid entry: {}: {:?}",
+            e.kind, texts[0]
+        ))),
+        _ => Err(Error::msg(format!("invalid entry: {:?}", texts[0]))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::table;
+    use std::io::Write;
+
+    const TEST_DATA: &str = "# Tuned for DB hosts
+; managed by hand
+
+vm.swappiness   =   10
+-net.ipv4.ip_forward=1
+kernel.sysrq = 0
+";
+
+    #[test]
+    fn ok_round_trip() {
+        for data in [
+            TEST_DATA,
+            "",
+            "foo = bar",
+            "a = b\r\n\r\n# c\r\n",
+            "  broken line\n\n",
+            "\u{FEFF}foo = bar\r\n",
+        ] {
+            assert_eq!(Document::parse(data).to_string(), data);
+        }
+        assert_eq!(
+            Document::parse("\u{FEFF}foo = bar\r\n")
+                .get("foo")
+                .as_deref(),
+            Some("bar")
+        );
+    }
+
+    #[test]
+    fn ok_set() {
+        let mut doc = Document::parse(TEST_DATA);
+        doc.set("vm.swappiness", "60").unwrap();
+        doc.set("net.ipv4.ip_forward", "0").unwrap();
+        assert_eq!(
+            doc.to_string(),
+            "# Tuned for DB hosts
+; managed by hand
+
+vm.swappiness   =   60
+-net.ipv4.ip_forward=0
+kernel.sysrq = 0
+"
+        );
+        assert_eq!(doc.get("vm.swappiness").as_deref(), Some("60"));
+    }
+
+    #[test]
+    fn ok_set_new_key() {
+        let mut doc = Document::parse("foo = bar");
+        doc.set("baz", "qux").unwrap();
+        assert_eq!(doc.to_string(), "foo = bar\nbaz = qux\n");
+
+        let mut doc = Document::parse("foo = bar\r\n");
+        doc.set("baz", "qux").unwrap();
+        assert_eq!(doc.to_string(), "foo = bar\r\nbaz = qux\r\n");
+    }
+
+    #[test]
+    fn ok_remove() {
+        let mut doc = Document::parse(TEST_DATA);
+        assert!(doc.remove("vm.swappiness"));
+        assert!(!doc.remove("vm.swappiness"));
+        assert_eq!(
+            doc.to_string(),
+            "# Tuned for DB hosts
+; managed by hand
+
+-net.ipv4.ip_forward=1
+kernel.sysrq = 0
+"
+        );
+    }
+
+    #[test]
+    fn ok_insert_after() {
+        let mut doc = Document::parse(TEST_DATA);
+        assert!(doc
+            .insert_after("vm.swappiness", "vm.dirty_ratio", "15")
+            .unwrap());
+        assert!(!doc
+            .insert_after("vm.overcommit_memory", "foo", "bar")
+            .unwrap());
+        assert_eq!(
+            doc.to_string(),
+            "# Tuned for DB hosts
+; managed by hand
+
+vm.swappiness   =   10
+vm.dirty_ratio = 15
+-net.ipv4.ip_forward=1
+kernel.sysrq = 0
+"
+        );
+    }
+
+    #[test]
+    fn ok_comment_out() {
+        let mut doc = Document::parse(TEST_DATA);
+        assert!(doc.comment_out("kernel.sysrq"));
+        assert!(!doc.comment_out("kernel.sysrq"));
+        assert_eq!(doc.get("kernel.sysrq").as_deref(), None);
+
+        let map = doc.to_config().unwrap();
+        let kernel = map.get("kernel");
+        assert!(kernel.is_none());
+
+        assert!(table(&map, "vm").contains_key("swappiness"));
+    }
+
+    #[test]
+    fn ng_set_invalid_entry() {
+        let mut doc = Document::parse(TEST_DATA);
+        assert!(doc.set("foo bar", "baz").is_err());
+        assert!(doc.set("foo", "").is_err());
+        assert!(doc.set("foo", "bar\nbaz = qux").is_err());
+        assert_eq!(doc.to_string(), TEST_DATA);
+    }
+
+    #[test]
+    fn ok_parse_with() {
+        let mut doc = Document::parse_with(
+            "kernel.domainname =\n  # indented\n",
+            ParseOptions::systemd(),
+        );
+        assert_eq!(doc.get("kernel.domainname").as_deref(), Some(""));
+        doc.set("kernel.domainname", "example.com").unwrap();
+        doc.set("kernel.hostname", "").unwrap();
+        assert_eq!(
+            doc.to_string(),
+            "kernel.domainname = example.com\n  # indented\nkernel.hostname = \n"
+        );
+        assert!(doc.to_config().is_ok());
+
+        let mut doc = Document::parse("kernel.domainname =\n");
+        doc.set("kernel.domainname", "example.com").unwrap();
+        assert_eq!(
+            doc.to_string(),
+            "kernel.domainname =\nkernel.domainname = example.com\n"
+        );
+        assert!(doc.set("kernel.hostname", "").is_err());
+
+        let mut doc = Document::parse_with(
+            "net.ipv4.conf.eth0/100.rp_filter = 2\n",
+            ParseOptions::freebsd(),
+        );
+        assert!(!doc.remove("net.ipv4.conf.eth0.100.rp_filter"));
+        assert!(doc.remove("net.ipv4.conf.eth0/100.rp_filter"));
+    }
+
+    #[test]
+    fn ok_set_with_slash_separator() {
+        let mut doc = Document::parse("net/ipv4/conf/eth0.100/rp_filter = 2\n");
+        doc.set("net.ipv4.conf.eth0/100.rp_filter", "1").unwrap();
+        assert_eq!(doc.to_string(), "net/ipv4/conf/eth0.100/rp_filter = 1\n");
+    }
+
+    #[test]
+    fn ok_inline_comments() {
+        let mut doc =
+            Document::parse_with("kern.maxfiles = 65536 # raised\n", ParseOptions::freebsd());
+        assert_eq!(doc.get("kern.maxfiles").as_deref(), Some("65536"));
+        doc.set("kern.maxfiles", "131072").unwrap();
+        assert_eq!(doc.to_string(), "kern.maxfiles = 131072 # raised\n");
+        assert_eq!(doc.get("kern.maxfiles").as_deref(), Some("131072"));
+    }
+
+    #[test]
+    fn ok_quoted_values() {
+        let options = ParseOptions::new().quoted_values(true);
+        let mut doc = Document::parse_with("foo = 'bar baz'\n", options.clone());
+        assert_eq!(doc.get("foo").as_deref(), Some("bar baz"));
+        doc.set("foo", "it's").unwrap();
+        assert_eq!(doc.to_string(), "foo = 'it\\'s'\n");
+        assert_eq!(doc.get("foo").as_deref(), Some("it's"));
+
+        let mut doc = Document::parse_with("", options);
+        doc.set("foo", " padded ").unwrap();
+        assert_eq!(doc.to_string(), "foo = \" padded \"\n");
+        assert_eq!(doc.get("foo").as_deref(), Some(" padded "));
+    }
+
+    #[test]
+    fn ok_sections() {
+        let options = ParseOptions::new().sections(true);
+        let mut doc = Document::parse_with("[net]\nip = 1\n", options.clone());
+        assert_eq!(doc.get("net.ip").as_deref(), Some("1"));
+        assert_eq!(doc.get("ip"), None);
+        doc.set("net.ip", "2").unwrap();
+        doc.set("net.ipv6", "0").unwrap();
+        doc.set("kernel.sysrq", "1").unwrap();
+        assert_eq!(
+            doc.to_string(),
+            "[net]\nip = 2\nipv6 = 0\n[]\nkernel.sysrq = 1\n"
+        );
+
+        let mut doc = Document::parse_with("[net]\nip = 1\nipv6 = 0\n", options);
+        assert!(doc.insert_after("net.ip", "kernel.sysrq", "1").unwrap());
+        assert_eq!(
+            doc.to_string(),
+            "[net]\nip = 1\n[]\nkernel.sysrq = 1\n[net]\nipv6 = 0\n"
+        );
+        assert_eq!(doc.get("net.ipv6").as_deref(), Some("0"));
+        assert!(doc.to_config().is_ok());
+    }
+
+    #[test]
+    fn ok_line_continuation() {
+        let options = ParseOptions::new().line_continuation(true);
+        let mut doc = Document::parse_with("a = 1 \\\n 2\nb = 4\n", options.clone());
+        assert_eq!(doc.get("a").as_deref(), Some("1  2"));
+        doc.set("a", "3").unwrap();
+        assert_eq!(doc.to_string(), "a = 3\nb = 4\n");
+        assert!(doc.to_config().is_ok());
+
+        let mut doc = Document::parse_with("a = 1 \\\n 2\nb = 4\n", options);
+        assert!(doc.comment_out("a"));
+        assert_eq!(doc.to_string(), "# a = 1 \\\n#  2\nb = 4\n");
+        assert!(doc.remove("b"));
+        assert_eq!(doc.get("a"), None);
+    }
+
+    #[test]
+    fn ok_load_with() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"foo = bar\xff\nbaz = 1\n").unwrap();
+
+        assert!(Document::load(file.path()).is_err());
+        let options = ParseOptions::new().lossy_utf8(true);
+        let doc = Document::load_with(file.path(), options).unwrap();
+        assert_eq!(doc.get("foo").as_deref(), Some("bar\u{FFFD}"));
+        assert_eq!(doc.get("baz").as_deref(), Some("1"));
+    }
+}
@@ -0,0 +1,212 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+
+use crate::split_key;
+
+/// The set of keys a glob pattern can expand to, usually the files under `/proc/sys`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyUniverse {
+    keys: Vec<Vec<String>>,
+}
+
+impl KeyUniverse {
+    pub fn from_keys<I, S>(keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let keys = keys
+            .into_iter()
+            .map(|k| {
+                split_key(k.as_ref())
+                    .into_iter()
+                    .map(|c| c.into_owned())
+                    .collect()
+            })
+            .collect();
+        Self { keys }
+    }
+
+    /// Collects every regular file below `root` as a key, e.g. `<root>/net/ipv4/ip_forward`
+    /// becomes `net.ipv4.ip_forward`. Pass `/proc/sys` for the running kernel.
+    pub fn from_proc_root(root: impl AsRef<Path>) -> Result<Self> {
+        let mut keys = vec![];
+        walk(root.as_ref(), &mut vec![], &mut keys)?;
+        keys.sort();
+        Ok(Self { keys })
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[String]> {
+        self.keys.iter().map(Vec::as_slice)
+    }
+}
+
+fn walk(dir: &Path, prefix: &mut Vec<String>, keys: &mut Vec<Vec<String>>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let file_type = entry.file_type()?;
+        prefix.push(name);
+        if file_type.is_dir() {
+            walk(&entry.path(), prefix, keys)?;
+        } else if file_type.is_file() {
+            keys.push(prefix.clone());
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+pub(crate) fn is_glob(key: &str) -> bool {
+    key.contains(['*', '?', '['])
+}
+
+// Matches component by component, so `*` never crosses a separator.
+pub(crate) fn matches<P: AsRef<str>, K: AsRef<str>>(pattern: &[P], key: &[K]) -> bool {
+    pattern.len() == key.len()
+        && pattern.iter().zip(key).all(|(p, k)| {
+            let p = p.as_ref().chars().collect::<Vec<char>>();
+            let k = k.as_ref().chars().collect::<Vec<char>>();
+            fnmatch(&p, &k)
+        })
+}
+
+// fnmatch(3) without flags: `*`, `?`, and bracket expressions with `!`/`^` negation and ranges.
+fn fnmatch(p: &[char], s: &[char]) -> bool {
+    let (mut pi, mut si) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while si < s.len() {
+        let step = match p.get(pi) {
+            Some('*') => {
+                star = Some((pi, si));
+                pi += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match_bracket(&p[pi..], s[si]),
+            Some(c) if *c == s[si] => Some(1),
+            _ => None,
+        };
+        match (step, star) {
+            (Some(len), _) => {
+                pi += len;
+                si += 1;
+            }
+            (None, Some((star_pi, star_si))) => {
+                pi = star_pi + 1;
+                si = star_si + 1;
+                star = Some((star_pi, star_si + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+// Returns the length of the bracket expression at the start of `p` if it matches `c`.
+// An unterminated `[` is matched literally.
+fn match_bracket(p: &[char], c: char) -> Option<usize> {
+    let mut i = 1;
+    let negate = matches!(p.get(i), Some('!') | Some('^'));
+    if negate {
+        i += 1;
+    }
+    let mut found = false;
+    let mut first = true;
+    while i < p.len() && (first || p[i] != ']') {
+        first = false;
+        if p.get(i + 1) == Some(&'-') && p.get(i + 2).is_some_and(|e| *e != ']') {
+            found |= p[i] <= c && c <= p[i + 2];
+            i += 3;
+        } else {
+            found |= p[i] == c;
+            i += 1;
+        }
+    }
+    if i >= p.len() {
+        return (c == '[').then_some(1);
+    }
+    (found != negate).then_some(i + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(p: &str, s: &str) -> bool {
+        let p = p.chars().collect::<Vec<char>>();
+        let s = s.chars().collect::<Vec<char>>();
+        fnmatch(&p, &s)
+    }
+
+    #[test]
+    fn ok_fnmatch() {
+        assert!(glob("*", "eth0"));
+        assert!(glob("*", ""));
+        assert!(glob("eth*", "eth0.100"));
+        assert!(glob("e?h0", "eth0"));
+        assert!(glob("eth[0-9]", "eth3"));
+        assert!(glob("eth[!0-9]", "ethx"));
+        assert!(glob("*_filter", "rp_filter"));
+        assert!(glob("a*b*c", "axxbyyc"));
+        assert!(glob("[]]", "]"));
+        assert!(glob("[", "["));
+
+        assert!(!glob("eth*", "lo"));
+        assert!(!glob("e?h0", "eh0"));
+        assert!(!glob("eth[0-9]", "ethx"));
+        assert!(!glob("eth[!0-9]", "eth3"));
+        assert!(!glob("a*b*c", "axxbyy"));
+    }
+
+    #[test]
+    fn ok_matches() {
+        let pattern = split_key("net.ipv4.conf.*.rp_filter");
+        assert!(matches(
+            &pattern,
+            &split_key("net.ipv4.conf.eth0.rp_filter")
+        ));
+        assert!(matches(
+            &pattern,
+            &split_key("net/ipv4/conf/eth0.100/rp_filter")
+        ));
+        assert!(!matches(
+            &pattern,
+            &split_key("net.ipv4.conf.eth0.accept_redirects")
+        ));
+        assert!(!matches(&pattern, &split_key("net.ipv4.conf.rp_filter")));
+    }
+
+    #[test]
+    fn ok_from_proc_root() {
+        let root = tempfile::tempdir().unwrap();
+        for key in [
+            "net/ipv4/ip_forward",
+            "net/ipv4/conf/eth0.100/rp_filter",
+            "kernel/sysrq",
+        ] {
+            let path = root.path().join(key);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "0\n").unwrap();
+        }
+
+        let universe = KeyUniverse::from_proc_root(root.path()).unwrap();
+        assert_eq!(
+            universe,
+            KeyUniverse::from_keys([
+                "kernel.sysrq",
+                "net/ipv4/conf/eth0.100/rp_filter",
+                "net.ipv4.ip_forward",
+            ])
+        );
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::{
+    glob, read_into, EntryRef, IncludeFailure, Origin, ParseError, ParseErrorKind, ParseOptions,
+    ParseReport,
+};
+
+// Reads the files named by the `include` line `entry` of `path` into `report`. The outer `Err` is
+// an I/O error while reading an included file; the inner one is a problem with the include line
+// itself. Errors inside included files are added to `report` directly.
+pub(crate) fn include(
+    report: &mut ParseReport,
+    entry: &EntryRef,
+    path: Option<&Path>,
+    options: &ParseOptions,
+    recover: bool,
+    chain: &mut Vec<Origin>,
+) -> Result<Result<(), ParseError>> {
+    let error = |kind: ParseErrorKind| ParseError {
+        path: path.map(Path::to_path_buf),
+        line: entry.line,
+        column: entry.column,
+        text: entry.text.to_string(),
+        kind,
+        include_chain: vec![],
+    };
+
+    if chain.len() >= options.max_include_depth {
+        return Ok(Err(error(ParseErrorKind::IncludeDepth {
+            limit: options.max_include_depth,
+        })));
+    }
+    let pattern = match path.and_then(Path::parent) {
+        Some(dir) => dir.join(&*entry.value),
+        None => PathBuf::from(&*entry.value),
+    };
+    let files = match files_of(&pattern) {
+        Ok(files) => files,
+        Err(e) => {
+            return Ok(Err(error(ParseErrorKind::IncludeFailed(Box::new(
+                IncludeFailure {
+                    path: pattern,
+                    message: e.to_string(),
+                },
+            )))))
+        }
+    };
+
+    // The files being read, from the outermost one to `path`.
+    let open = chain
+        .iter()
+        .filter_map(|o| o.path.as_deref())
+        .chain(path)
+        .filter_map(|p| fs::canonicalize(p).ok())
+        .collect::<Vec<PathBuf>>();
+    for file in files {
+        let opened = fs::canonicalize(&file)
+            .map(|canonical| open.contains(&canonical))
+            .and_then(|cycle| Ok((cycle, File::open(&file)?)));
+        let reader = match opened {
+            Ok((true, _)) => return Ok(Err(error(ParseErrorKind::IncludeCycle { path: file }))),
+            Ok((false, f)) => BufReader::new(f),
+            Err(e) => {
+                return Ok(Err(error(ParseErrorKind::IncludeFailed(Box::new(
+                    IncludeFailure {
+                        path: file,
+                        message: e.to_string(),
+                    },
+                )))))
+            }
+        };
+
+        chain.push(Origin {
+            path: path.map(Path::to_path_buf),
+            line: entry.line,
+        });
+        let result = read_into(report, reader, Some(&file), options, recover, chain);
+        chain.pop();
+        result?;
+        if !report.errors.is_empty() && !recover {
+            break;
+        }
+    }
+    Ok(Ok(()))
+}
+
+// Expands a glob in the file name of `pattern` into the matching files, in sorted order. A
+// pattern without a glob names a single file.
+fn files_of(pattern: &Path) -> io::Result<Vec<PathBuf>> {
+    let name = pattern
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if !glob::is_glob(&name) {
+        return Ok(vec![pattern.to_path_buf()]);
+    }
+
+    let dir = match pattern.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if !entry.file_type()?.is_dir() && glob::matches(&[&name], &[&file_name]) {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_str;
+    use crate::tests::{table, value};
+
+    fn write(root: &Path, path: &str, data: &str) -> PathBuf {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn ok_include() {
+        let root = tempfile::tempdir().unwrap();
+        let main = write(
+            root.path(),
+            "sysctl.conf",
+            "kernel.sysrq = 1\ninclude common.conf\ninclude conf.d/*.conf\nvm.swappiness = 60\n",
+        );
+        write(
+            root.path(),
+            "common.conf",
+            "kernel.sysrq = 16\nkernel.panic = 0\n",
+        );
+        write(root.path(), "conf.d/20-panic.conf", "kernel.panic = 10\n");
+        write(
+            root.path(),
+            "conf.d/10-vm.conf",
+            "vm.swappiness = 10\nvm.overcommit_memory = 1\n",
+        );
+        write(root.path(), "conf.d/README", "not a config\n");
+
+        let map = ParseOptions::new().includes(true).load_path(&main).unwrap();
+
+        let kernel = table(&map, "kernel");
+        let v = value(kernel, "sysrq");
+        assert_eq!(v, "16");
+        let v = value(kernel, "panic");
+        assert_eq!(v, "10");
+
+        let vm = table(&map, "vm");
+        let v = value(vm, "swappiness");
+        assert_eq!(v, "60");
+        assert_eq!(
+            vm.keys().collect::<Vec<_>>(),
+            vec!["swappiness", "overcommit_memory"]
+        );
+
+        let origin = map.entry_origin("kernel.panic").unwrap();
+        assert_eq!(
+            origin.path.as_deref(),
+            Some(root.path().join("conf.d/20-panic.conf").as_path())
+        );
+        assert_eq!(origin.line, 1);
+    }
+
+    #[test]
+    fn ok_include_disabled() {
+        let err = parse_str("include common.conf\n").unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::MissingDelimiter);
+
+        let map = ParseOptions::new()
+            .includes(true)
+            .parse_str("include = 1\n")
+            .unwrap();
+        assert!(map.contains_key("include"));
+    }
+
+    #[test]
+    fn ng_include_error_chain() {
+        let root = tempfile::tempdir().unwrap();
+        let a = write(
+            root.path(),
+            "a.conf",
+            "kernel.sysrq = 1\n\ninclude b.conf\n",
+        );
+        let b = write(root.path(), "b.conf", "# b\ninclude c.conf\n");
+        let c = write(root.path(), "c.conf", "vm.swappiness = 10\nbroken line\n");
+
+        let err = ParseOptions::new()
+            .includes(true)
+            .load_path(&a)
+            .unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::MissingDelimiter);
+        assert_eq!(err.path.as_deref(), Some(c.as_path()));
+        assert_eq!(err.line, 2);
+        assert_eq!(
+            err.include_chain,
+            vec![
+                Origin {
+                    path: Some(a.clone()),
+                    line: 3
+                },
+                Origin {
+                    path: Some(b.clone()),
+                    line: 2
+                },
+            ]
+        );
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{}:3 -> {}:2 -> {}:2:1: missing '=' delimiter: \"broken line\"",
+                a.display(),
+                b.display(),
+                c.display()
+            ),
+        );
+    }
+
+    #[test]
+    fn ng_include_cycle() {
+        let root = tempfile::tempdir().unwrap();
+        let a = write(root.path(), "a.conf", "include b.conf\n");
+        let b = write(
+            root.path(),
+            "b.conf",
+            "kernel.sysrq = 1\ninclude ./a.conf\n",
+        );
+
+        let err = ParseOptions::new()
+            .includes(true)
+            .load_path(&a)
+            .unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::IncludeCycle {
+                path: root.path().join("./a.conf")
+            }
+        );
+        assert_eq!(err.path.as_deref(), Some(b.as_path()));
+        assert_eq!(err.line, 2);
+        assert_eq!(
+            err.include_chain,
+            vec![Origin {
+                path: Some(a.clone()),
+                line: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn ng_include_depth() {
+        let root = tempfile::tempdir().unwrap();
+        let a = write(root.path(), "a.conf", "include b.conf\n");
+        let b = write(root.path(), "b.conf", "include c.conf\n");
+        write(root.path(), "c.conf", "kernel.sysrq = 1\n");
+
+        let options = ParseOptions::new().includes(true).max_include_depth(1);
+        let err = options.load_path(&a).unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::IncludeDepth { limit: 1 });
+        assert_eq!(err.path.as_deref(), Some(b.as_path()));
+
+        assert!(options.max_include_depth(2).load_path(&a).is_ok());
+    }
+
+    #[test]
+    fn ng_include_missing() {
+        let root = tempfile::tempdir().unwrap();
+        let a = write(
+            root.path(),
+            "a.conf",
+            "include missing.conf\ninclude missing.d/*.conf\nkernel.sysrq = 1\n",
+        );
+
+        let report = ParseOptions::new()
+            .includes(true)
+            .load_path_recovering(&a)
+            .unwrap();
+        assert_eq!(report.errors.len(), 2);
+        let ParseErrorKind::IncludeFailed(failure) = &report.errors[0].kind else {
+            panic!(
+                "expected IncludeFailed, but got {:?}",
+                report.errors[0].kind
+            );
+        };
+        assert_eq!(failure.path, root.path().join("missing.conf"));
+        assert_eq!(report.errors[1].line, 2);
+        assert!(report.config.contains_key("kernel"));
+    }
+}
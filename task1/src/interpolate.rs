@@ -0,0 +1,150 @@
+use std::{borrow::Cow, collections::HashMap, fmt};
+
+use crate::Origin;
+
+/// Where `${VAR}` references in values are looked up, see `ParseOptions::variables`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VariableSource {
+    /// The environment of the current process.
+    Env,
+    Map(HashMap<String, String>),
+}
+
+impl VariableSource {
+    pub fn get(&self, name: &str) -> Option<String> {
+        match self {
+            VariableSource::Env => std::env::var(name).ok(),
+            VariableSource::Map(vars) => vars.get(name).cloned(),
+        }
+    }
+}
+
+impl From<HashMap<String, String>> for VariableSource {
+    fn from(vars: HashMap<String, String>) -> Self {
+        VariableSource::Map(vars)
+    }
+}
+
+/// A `${VAR}` reference without a default whose variable is not set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnresolvedVariable {
+    pub name: String,
+    pub origin: Origin,
+}
+
+/// Every unresolved variable of a parse, in the order they appear. Functions returning
+/// `anyhow::Result` wrap this type when all lines were valid but some variables were not set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnresolvedVariables {
+    pub variables: Vec<UnresolvedVariable>,
+}
+
+impl fmt::Display for UnresolvedVariables {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unresolved variables: ")?;
+        for (i, variable) in self.variables.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} at {}", variable.name, variable.origin)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for UnresolvedVariables {}
+
+// Replaces `${NAME}` with the value of NAME and `${NAME:-default}` with the value of NAME, or
+// `default` if NAME is unset or empty. The names of unset variables without a default are pushed
+// to `unresolved` and their references are kept as written. A `$` that doesn't start a reference
+// to a valid name, e.g. `${log.dir}`, is literal.
+pub(crate) fn expand<'a>(
+    value: &'a str,
+    source: &VariableSource,
+    unresolved: &mut Vec<String>,
+) -> Cow<'a, str> {
+    if !value.contains("${") {
+        return Cow::Borrowed(value);
+    }
+
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let Some(len) = rest[start..].find('}').map(|end| end + 1) else {
+            rest = &rest[start..];
+            break;
+        };
+        let reference = &rest[start..start + len];
+        let (name, default) = match reference[2..len - 1].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&reference[2..len - 1], None),
+        };
+        match (is_name(name).then(|| source.get(name)), default) {
+            (Some(Some(v)), Some(default)) => {
+                expanded.push_str(if v.is_empty() { default } else { &v })
+            }
+            (Some(Some(v)), None) => expanded.push_str(&v),
+            (Some(None), Some(default)) => expanded.push_str(default),
+            (Some(None), None) => {
+                unresolved.push(name.to_string());
+                expanded.push_str(reference);
+            }
+            (None, _) => expanded.push_str(reference),
+        }
+        rest = &rest[start + len..];
+    }
+    expanded.push_str(rest);
+    Cow::Owned(expanded)
+}
+
+fn is_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> VariableSource {
+        HashMap::from([
+            ("LOG_DIR".to_string(), "/var/log".to_string()),
+            ("EMPTY".to_string(), "".to_string()),
+        ])
+        .into()
+    }
+
+    #[test]
+    fn ok_expand() {
+        let mut unresolved = vec![];
+        assert_eq!(
+            expand("${LOG_DIR}/app.log", &vars(), &mut unresolved),
+            "/var/log/app.log"
+        );
+        assert_eq!(
+            expand("${MISSING:-/tmp}/app.log", &vars(), &mut unresolved),
+            "/tmp/app.log"
+        );
+        assert_eq!(expand("${EMPTY:-x}${EMPTY}", &vars(), &mut unresolved), "x");
+        assert_eq!(
+            expand("${LOG_DIR:-/tmp}", &vars(), &mut unresolved),
+            "/var/log"
+        );
+        assert_eq!(
+            expand("$LOG_DIR ${log.dir} ${LOG_DIR", &vars(), &mut unresolved),
+            "$LOG_DIR ${log.dir} ${LOG_DIR"
+        );
+        assert!(matches!(
+            expand("100", &vars(), &mut unresolved),
+            Cow::Borrowed(_)
+        ));
+        assert!(unresolved.is_empty());
+
+        assert_eq!(
+            expand("${A}/${LOG_DIR}/${B}", &vars(), &mut unresolved),
+            "${A}//var/log/${B}"
+        );
+        assert_eq!(unresolved, vec!["A", "B"]);
+    }
+}
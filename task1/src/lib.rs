@@ -1,277 +1,3351 @@
-use std::{collections::HashMap, io::{BufRead, BufReader, Cursor, Read}};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    io::{BufRead, Read},
+    path::{Path, PathBuf},
+};
 
-use anyhow::{Error, Result};
+use anyhow::Result;
+use indexmap::IndexMap;
 
-pub enum SysctlConfigValue{
+mod borrowed;
+mod dialect;
+mod document;
+mod glob;
+mod include;
+mod interpolate;
+mod operation;
+mod options;
+mod reference;
+mod system;
+mod table;
+
+pub use borrowed::{parse_borrowed, SysctlConfigRef, SysctlConfigValueRef};
+pub use dialect::Dialect;
+pub use document::Document;
+pub use glob::KeyUniverse;
+pub use interpolate::{UnresolvedVariable, UnresolvedVariables, VariableSource};
+pub use operation::{Operation, OperationKind};
+pub use options::{ConflictPolicy, ParseOptions};
+pub use reference::ReferenceError;
+pub use system::{load_system, SystemRoots};
+
+use table::{Slot, Table};
+
+/// Values compare equal when they have the same whitespace-separated fields, so
+/// `1024\t65000` equals `1024 65000`.
+#[derive(Debug, Clone)]
+pub enum SysctlConfigValue {
     String(String),
     SysctlConfig(SysctlConfig),
 }
 
-type SysctlConfig = HashMap<String, SysctlConfigValue>;
+impl SysctlConfigValue {
+    /// Splits a multi-value entry such as `net.ipv4.tcp_rmem = 4096 87380 6291456` into its
+    /// fields. Returns `None` for a table.
+    pub fn as_fields(&self) -> Option<Vec<&str>> {
+        match self {
+            SysctlConfigValue::String(v) => Some(v.split_whitespace().collect()),
+            SysctlConfigValue::SysctlConfig(_) => None,
+        }
+    }
+}
+
+impl PartialEq for SysctlConfigValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (SysctlConfigValue::String(a), SysctlConfigValue::String(b)) => {
+                a.split_whitespace().eq(b.split_whitespace())
+            }
+            (SysctlConfigValue::SysctlConfig(a), SysctlConfigValue::SysctlConfig(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for SysctlConfigValue {}
+
+/// Where a leaf value was assigned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    pub path: Option<PathBuf>,
+    pub line: usize,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{}:{}", path.display(), self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+/// An assignment whose key contains glob characters, e.g. `net.ipv4.conf.*.rp_filter = 2`.
+/// `key_path` holds the pattern of each component.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobEntry {
+    pub key_path: Vec<String>,
+    pub value: String,
+    pub origin: Origin,
+}
+
+/// A table of sysctl entries. Keys iterate in the order they first appeared in the input.
+///
+/// Glob assignments and `-key` exclusions are kept on the top-level table as written; use
+/// `expand_globs` to turn them into concrete entries.
+///
+/// Two configs are equal when they assign equal values to the same keys and have the same
+/// pending operations, regardless of key order, of where the values came from and of their
+/// inline comments.
+#[derive(Debug, Clone, Default)]
+pub struct SysctlConfig {
+    entries: IndexMap<String, SysctlConfigValue>,
+    origins: HashMap<String, Origin>,
+    extras: Box<Extras>,
+}
+
+/// The parts of a table that most configs leave empty, boxed to keep `SysctlConfig` small.
+#[derive(Debug, Clone, Default)]
+struct Extras {
+    comments: HashMap<String, String>,
+    globs: Vec<GlobEntry>,
+    exclusions: Vec<Vec<String>>,
+    operations: Vec<Operation>,
+}
+
+impl PartialEq for SysctlConfig {
+    fn eq(&self, other: &Self) -> bool {
+        let same_globs = self.extras.globs.len() == other.extras.globs.len()
+            && self
+                .extras
+                .globs
+                .iter()
+                .zip(other.extras.globs.iter())
+                .all(|(a, b)| {
+                    a.key_path == b.key_path
+                        && a.value.split_whitespace().eq(b.value.split_whitespace())
+                });
+        let same_operations = self
+            .extras
+            .operations
+            .iter()
+            .map(|o| (&o.key_path, &o.kind))
+            .eq(other
+                .extras
+                .operations
+                .iter()
+                .map(|o| (&o.key_path, &o.kind)));
+        self.entries == other.entries
+            && same_globs
+            && self.extras.exclusions == other.extras.exclusions
+            && same_operations
+    }
+}
+
+impl Eq for SysctlConfig {}
+
+impl SysctlConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&SysctlConfigValue> {
+        self.entries.get(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn keys(&self) -> indexmap::map::Keys<'_, String, SysctlConfigValue> {
+        self.entries.keys()
+    }
+
+    pub fn iter(&self) -> indexmap::map::Iter<'_, String, SysctlConfigValue> {
+        self.entries.iter()
+    }
+
+    /// Returns the file and line that assigned the leaf at the dotted `key`,
+    /// e.g. `entry_origin("net.ipv4.ip_forward")`. A key parsed with
+    /// `ParseOptions::slash_separator` off, like `kern.a/b`, is found as written too.
+    pub fn entry_origin(&self, key: &str) -> Option<&Origin> {
+        self.origin_at(&split_key(key))
+            .or_else(|| self.origin_at(&key.split('.').collect::<Vec<&str>>()))
+    }
+
+    /// Returns the inline comment of the leaf at the dotted `key`, without the `#` or `;`.
+    /// Only set when parsing with `ParseOptions::inline_comments`. Keys are looked up like
+    /// in `entry_origin`.
+    pub fn entry_comment(&self, key: &str) -> Option<&str> {
+        self.comment_at(&split_key(key))
+            .or_else(|| self.comment_at(&key.split('.').collect::<Vec<&str>>()))
+    }
+
+    fn origin_at<S: AsRef<str>>(&self, keys: &[S]) -> Option<&Origin> {
+        let (last, parents) = keys.split_last()?;
+        self.table(parents)?.origins.get(last.as_ref())
+    }
+
+    fn comment_at<S: AsRef<str>>(&self, keys: &[S]) -> Option<&str> {
+        let (last, parents) = keys.split_last()?;
+        self.table(parents)?
+            .extras
+            .comments
+            .get(last.as_ref())
+            .map(String::as_str)
+    }
+
+    pub fn globs(&self) -> &[GlobEntry] {
+        &self.extras.globs
+    }
+
+    /// The key paths of the `-key` exclusions.
+    pub fn exclusions(&self) -> &[Vec<String>] {
+        &self.extras.exclusions
+    }
+
+    /// Resolves glob assignments against `universe`, following systemd-sysctl: keys that are
+    /// assigned explicitly keep their value, keys matching an exclusion are skipped, and a later
+    /// glob overrides an earlier one. The returned config has no globs or exclusions left.
+    pub fn expand_globs(&self, universe: &KeyUniverse) -> SysctlConfig {
+        let mut expanded = self.clone();
+        expanded.extras.globs.clear();
+        expanded.extras.exclusions.clear();
+
+        let exclusions = &self.extras.exclusions;
+        for key in universe.iter() {
+            if self.get_path(key).is_some() || exclusions.iter().any(|e| glob::matches(e, key)) {
+                continue;
+            }
+            let glob = self
+                .extras
+                .globs
+                .iter()
+                .rev()
+                .find(|g| glob::matches(&g.key_path, key));
+            if let Some(glob) = glob {
+                let _ = expanded.insert_path(
+                    key,
+                    Cow::Owned(glob.value.clone()),
+                    glob.origin.clone(),
+                    None,
+                    ConflictPolicy::FirstWins,
+                );
+            }
+        }
+        expanded
+    }
+
+    /// Applies `other` on top of this config. The operations of `other` are applied first, then
+    /// leaves in `other` replace whatever is at the same key here, tables are merged
+    /// recursively, and globs and exclusions are appended.
+    pub fn merge(&mut self, other: SysctlConfig) {
+        let SysctlConfig {
+            entries,
+            mut origins,
+            extras,
+        } = other;
+        let Extras {
+            mut comments,
+            globs,
+            exclusions,
+            operations,
+        } = *extras;
+        for op in operations {
+            self.apply(op);
+        }
+        for (key, value) in entries {
+            match (self.entries.get_mut(&key), value) {
+                (
+                    Some(SysctlConfigValue::SysctlConfig(m)),
+                    SysctlConfigValue::SysctlConfig(other),
+                ) => m.merge(other),
+                (_, value) => {
+                    match origins.remove(&key) {
+                        Some(origin) => self.origins.insert(key.clone(), origin),
+                        None => self.origins.remove(&key),
+                    };
+                    match comments.remove(&key) {
+                        Some(comment) => self.extras.comments.insert(key.clone(), comment),
+                        None => self.extras.comments.remove(&key),
+                    };
+                    self.entries.insert(key, value);
+                }
+            }
+        }
+        self.extras.globs.extend(globs);
+        self.extras.exclusions.extend(exclusions);
+    }
+
+    // The table holding the leaves below `parents`; `self` for an empty path.
+    fn table<S: AsRef<str>>(&self, parents: &[S]) -> Option<&SysctlConfig> {
+        if parents.is_empty() {
+            return Some(self);
+        }
+        match self.get_path(parents) {
+            Some(SysctlConfigValue::SysctlConfig(m)) => Some(m),
+            _ => None,
+        }
+    }
+
+    fn get_path<S: AsRef<str>>(&self, keys: &[S]) -> Option<&SysctlConfigValue> {
+        let (last, parents) = keys.split_last()?;
+        let mut m = self;
+        for key in parents {
+            match m.entries.get(key.as_ref()) {
+                Some(SysctlConfigValue::SysctlConfig(next_m)) => m = next_m,
+                _ => return None,
+            }
+        }
+        m.entries.get(last.as_ref())
+    }
+
+    // Every leaf with its key path, in insertion order.
+    fn leaves(&self) -> Vec<(Vec<String>, String)> {
+        let mut leaves = vec![];
+        for (key, value) in self.entries.iter() {
+            match value {
+                SysctlConfigValue::String(v) => leaves.push((vec![key.clone()], v.clone())),
+                SysctlConfigValue::SysctlConfig(m) => {
+                    leaves.extend(m.leaves().into_iter().map(|(mut keys, v)| {
+                        keys.insert(0, key.clone());
+                        (keys, v)
+                    }));
+                }
+            }
+        }
+        leaves
+    }
+}
+
+impl<'a> Table<'a> for SysctlConfig {
+    fn slot(&self, key: &str) -> Option<Slot<'_, Self>> {
+        match self.entries.get(key)? {
+            SysctlConfigValue::String(v) => Some(Slot::Leaf(v, &self.origins[key])),
+            SysctlConfigValue::SysctlConfig(m) => Some(Slot::Table(m)),
+        }
+    }
+
+    fn slots(&self) -> Box<dyn Iterator<Item = (&str, Slot<'_, Self>)> + '_> {
+        Box::new(self.entries.iter().map(|(key, value)| {
+            let slot = match value {
+                SysctlConfigValue::String(v) => Slot::Leaf(v, &self.origins[key]),
+                SysctlConfigValue::SysctlConfig(m) => Slot::Table(m),
+            };
+            (key.as_str(), slot)
+        }))
+    }
+
+    fn table_mut(&mut self, key: Cow<'a, str>) -> &mut Self {
+        if let Some(SysctlConfigValue::String(_)) = self.entries.get(key.as_ref()) {
+            self.origins.remove(key.as_ref());
+            self.extras.comments.remove(key.as_ref());
+            self.entries.insert(
+                key.to_string(),
+                SysctlConfigValue::SysctlConfig(SysctlConfig::new()),
+            );
+        }
+        match self
+            .entries
+            .entry(key.into_owned())
+            .or_insert_with(|| SysctlConfigValue::SysctlConfig(SysctlConfig::new()))
+        {
+            SysctlConfigValue::SysctlConfig(m) => m,
+            SysctlConfigValue::String(_) => unreachable!(),
+        }
+    }
+
+    fn existing_table_mut(&mut self, key: &str) -> Option<&mut Self> {
+        match self.entries.get_mut(key)? {
+            SysctlConfigValue::SysctlConfig(m) => Some(m),
+            SysctlConfigValue::String(_) => None,
+        }
+    }
+
+    fn set_leaf(
+        &mut self,
+        key: Cow<'a, str>,
+        value: Cow<'a, str>,
+        origin: Origin,
+        comment: Option<Cow<'a, str>>,
+    ) -> Option<(Cow<'a, str>, Origin)> {
+        let key = key.into_owned();
+        match comment {
+            Some(comment) => self
+                .extras
+                .comments
+                .insert(key.clone(), comment.into_owned()),
+            None => self.extras.comments.remove(&key),
+        };
+        let old_origin = self.origins.insert(key.clone(), origin);
+        let old_value = self
+            .entries
+            .insert(key, SysctlConfigValue::String(value.into_owned()));
+        match (old_value, old_origin) {
+            (Some(SysctlConfigValue::String(v)), Some(o)) => Some((Cow::Owned(v), o)),
+            _ => None,
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        self.origins.remove(key);
+        self.extras.comments.remove(key);
+        self.entries.shift_remove(key).is_some()
+    }
+
+    fn push_glob(&mut self, key_path: Vec<Cow<'a, str>>, value: Cow<'a, str>, origin: Origin) {
+        self.extras.globs.push(GlobEntry {
+            key_path: key_path.into_iter().map(Cow::into_owned).collect(),
+            value: value.into_owned(),
+            origin,
+        });
+    }
+
+    fn push_exclusion(&mut self, key_path: Vec<Cow<'a, str>>) {
+        self.extras
+            .exclusions
+            .push(key_path.into_iter().map(Cow::into_owned).collect());
+    }
+
+    fn operations_mut(&mut self) -> Option<&mut Vec<Operation>> {
+        Some(&mut self.extras.operations)
+    }
+}
+
+impl<'a> IntoIterator for &'a SysctlConfig {
+    type Item = (&'a String, &'a SysctlConfigValue);
+    type IntoIter = indexmap::map::Iter<'a, String, SysctlConfigValue>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    MissingDelimiter,
+    EmptyKey,
+    EmptyValue,
+    WhitespaceInKey,
+    /// A quoted value has no closing quote. Only with `ParseOptions::quoted_values`.
+    UnterminatedQuote,
+    /// A quoted value contains an escape other than `\n`, `\t`, `\\`, `\"` and `\'`.
+    InvalidEscape,
+    /// Something other than whitespace follows the closing quote of a value.
+    TrailingCharacters,
+    /// The key was already assigned at `other`. Only reported with `ParseOptions::deny_duplicates`.
+    DuplicateKey {
+        other: Box<Origin>,
+    },
+    /// The key is assigned as a value on one line and used as a table on another.
+    /// `other_key` and `other` name the earlier of the two lines.
+    LeafBranchConflict {
+        other_key: String,
+        other: Box<Origin>,
+    },
+    /// An `include` line names `path`, which is already being read further up the include chain.
+    IncludeCycle {
+        path: PathBuf,
+    },
+    /// An `include` line is nested deeper than `ParseOptions::max_include_depth`.
+    IncludeDepth {
+        limit: usize,
+    },
+    /// The file or directory named by an `include` line could not be read.
+    IncludeFailed(Box<IncludeFailure>),
+    /// A `[section]` header has no closing `]` or text after it. Only with
+    /// `ParseOptions::sections`.
+    InvalidSection,
+    /// The line is not valid UTF-8. `offset` is the byte offset of the first invalid byte from
+    /// the start of the input, and `column` counts the characters before it.
+    InvalidUtf8 {
+        offset: usize,
+    },
+    /// A `+=` or `!` line where operations can't be kept, i.e. in `ParseOptions::parse_borrowed`.
+    UnsupportedOperator,
+    /// The file is larger than `ParseOptions::max_file_size`. Reported on the line that crosses
+    /// the limit, after which the file is not read any further. `column` is the first
+    /// character past the limit, and `text` holds the line up to it.
+    FileTooLarge {
+        limit: usize,
+    },
+    /// The line is longer than `ParseOptions::max_line_length`. `column` is the first
+    /// character past the limit, and `text` holds the line up to it.
+    LineTooLong {
+        limit: usize,
+    },
+    /// The key has more components than `ParseOptions::max_key_depth`.
+    KeyTooDeep {
+        limit: usize,
+    },
+    /// The line would add more keys than `ParseOptions::max_keys`.
+    TooManyKeys {
+        limit: usize,
+    },
+    /// The value is longer than `ParseOptions::max_value_length`.
+    ValueTooLong {
+        limit: usize,
+    },
+}
+
+/// The file or directory an `include` line could not read, and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncludeFailure {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::MissingDelimiter => write!(f, "missing '=' delimiter"),
+            ParseErrorKind::EmptyKey => write!(f, "empty key"),
+            ParseErrorKind::EmptyValue => write!(f, "empty value"),
+            ParseErrorKind::WhitespaceInKey => write!(f, "whitespace in key"),
+            ParseErrorKind::UnterminatedQuote => write!(f, "unterminated quote"),
+            ParseErrorKind::InvalidEscape => write!(f, "invalid escape sequence"),
+            ParseErrorKind::TrailingCharacters => {
+                write!(f, "unexpected characters after closing quote")
+            }
+            ParseErrorKind::DuplicateKey { other } => {
+                write!(f, "duplicate key, already assigned at {}", other)
+            }
+            ParseErrorKind::LeafBranchConflict { other_key, other } => {
+                write!(
+                    f,
+                    "key is used both as a value and as a table, conflicting with {} at {}",
+                    other_key, other
+                )
+            }
+            ParseErrorKind::IncludeCycle { path } => {
+                write!(f, "include cycle through {}", path.display())
+            }
+            ParseErrorKind::IncludeDepth { limit } => {
+                write!(f, "includes nested deeper than {}", limit)
+            }
+            ParseErrorKind::IncludeFailed(failure) => write!(
+                f,
+                "cannot include {}: {}",
+                failure.path.display(),
+                failure.message
+            ),
+            ParseErrorKind::InvalidSection => write!(f, "invalid section header"),
+            ParseErrorKind::InvalidUtf8 { offset } => {
+                write!(f, "invalid UTF-8 at byte offset {}", offset)
+            }
+            ParseErrorKind::UnsupportedOperator => write!(f, "'+=' and '!' are not supported here"),
+            ParseErrorKind::FileTooLarge { limit } => write!(f, "file larger than {} bytes", limit),
+            ParseErrorKind::LineTooLong { limit } => write!(f, "line longer than {} bytes", limit),
+            ParseErrorKind::KeyTooDeep { limit } => {
+                write!(f, "key deeper than {} components", limit)
+            }
+            ParseErrorKind::TooManyKeys { limit } => write!(f, "more than {} keys", limit),
+            ParseErrorKind::ValueTooLong { limit } => {
+                write!(f, "value longer than {} bytes", limit)
+            }
+        }
+    }
+}
+
+/// An invalid line in a sysctl file.
+///
+/// `line` and `column` are 1-based. `path` is set when the input was read from a file.
+/// `include_chain` holds the `include` lines that led to the file, outermost first, and is
+/// empty for the file that was loaded directly.
+/// Functions returning `anyhow::Result` wrap this type, so callers can recover it with
+/// `err.downcast_ref::<ParseError>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub path: Option<PathBuf>,
+    pub line: usize,
+    pub column: usize,
+    pub text: String,
+    pub kind: ParseErrorKind,
+    pub include_chain: Vec<Origin>,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for origin in self.include_chain.iter() {
+            write!(f, "{} -> ", origin)?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, "{}:", path.display())?;
+        }
+        write!(
+            f,
+            "{}:{}: {}: {:?}",
+            self.line, self.column, self.kind, self.text
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A key assigned more than once. The later assignment (`new`) replaced the earlier one (`old`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Override {
+    pub key_path: Vec<String>,
+    pub old_value: String,
+    pub new_value: String,
+    pub old: Origin,
+    pub new: Origin,
+}
+
+impl fmt::Display for Override {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} = {:?} overrides {:?} assigned at {}",
+            self.new,
+            join_key(&self.key_path),
+            self.new_value,
+            self.old_value,
+            self.old
+        )
+    }
+}
+
+/// The outcome of a recovering parse: every valid line is applied to `config`, and every
+/// invalid line is recorded in `errors` instead of aborting the parse. `overrides` are warnings
+/// about keys that were assigned more than once. `unresolved` lists the variables that
+/// `ParseOptions::variables` could not expand; their references are left in the values as written.
+#[derive(Debug, Clone)]
+pub struct ParseReport<C = SysctlConfig> {
+    pub config: C,
+    pub errors: Vec<ParseError>,
+    pub overrides: Vec<Override>,
+    pub unresolved: Vec<UnresolvedVariable>,
+    // The keys added so far, for `ParseOptions::max_keys`.
+    keys: usize,
+}
+
+impl<C> ParseReport<C> {
+    fn new(config: C) -> Self {
+        Self {
+            config,
+            errors: vec![],
+            overrides: vec![],
+            unresolved: vec![],
+            keys: 0,
+        }
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty() || !self.unresolved.is_empty()
+    }
+
+    /// Returns the config if no line was invalid and every variable was resolved. Otherwise
+    /// returns the first `ParseError`, or if there is none, all unresolved variables as
+    /// `UnresolvedVariables`.
+    pub fn into_result(mut self) -> Result<C> {
+        if !self.errors.is_empty() {
+            Err(self.errors.swap_remove(0).into())
+        } else if !self.unresolved.is_empty() {
+            Err(UnresolvedVariables {
+                variables: self.unresolved,
+            }
+            .into())
+        } else {
+            Ok(self.config)
+        }
+    }
+}
+
+pub fn load_sysctl(path: String) -> Result<SysctlConfig> {
+    load_path(path)
+}
+
+/// Like `load_sysctl`, parsing with `options` instead of the defaults.
+pub fn load_sysctl_with_options(path: String, options: &ParseOptions) -> Result<SysctlConfig> {
+    options.load_path(path)
+}
+
+/// Like `load_sysctl`, but keeps going after an invalid line and reports all of them.
+/// Only I/O failures are returned as `Err`.
+pub fn load_sysctl_recovering(path: String) -> Result<ParseReport> {
+    ParseOptions::default().load_path_recovering(path)
+}
+
+pub fn load_path(path: impl AsRef<Path>) -> Result<SysctlConfig> {
+    ParseOptions::default().load_path(path)
+}
+
+pub fn parse_reader(reader: impl BufRead) -> Result<SysctlConfig> {
+    ParseOptions::default().parse_reader(reader)
+}
+
+pub fn parse_str(s: &str) -> Result<SysctlConfig> {
+    ParseOptions::default().parse_str(s)
+}
+
+fn load_sysctl_from_reader(
+    reader: impl BufRead,
+    path: Option<&Path>,
+    options: &ParseOptions,
+    recover: bool,
+) -> Result<ParseReport> {
+    let mut report = ParseReport::new(SysctlConfig::new());
+    read_into(&mut report, reader, path, options, recover, &mut vec![])?;
+    Ok(report)
+}
+
+// Adds the entries of `reader` to `report`, following includes. `chain` holds the include lines
+// that led to `path`. Stops at the first invalid line unless `recover` is set.
+fn read_into(
+    report: &mut ParseReport,
+    reader: impl BufRead,
+    path: Option<&Path>,
+    options: &ParseOptions,
+    recover: bool,
+    chain: &mut Vec<Origin>,
+) -> Result<()> {
+    let mut lexer = Lexer::new(ReadLines::new(reader), path, options);
+    while let Some(entry) = lexer.next_entry() {
+        let result = match entry {
+            Ok(entry) if entry.kind == EntryKind::Include => {
+                include::include(report, &entry, path, options, recover, chain)?
+            }
+            Ok(entry) => insert_entry(report, entry, path, options),
+            Err(e) => Err(e.downcast::<ParseError>()?),
+        };
+        if let Err(mut e) = result {
+            e.include_chain = chain.clone();
+            report.errors.push(e);
+        }
+        if !report.errors.is_empty() && !recover {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// `key = value`
+    Assign,
+    /// `-key` without a value, excluding `key` from glob expansion.
+    Exclude,
+    /// `key += value`. Only with `ParseOptions::operators`.
+    Append,
+    /// `!key`, with an empty value. Only with `ParseOptions::operators`.
+    Unset,
+    /// `include pattern`, with the pattern as the value and an empty key path. Only with
+    /// `ParseOptions::includes`; `Entries` yields it without reading the included files.
+    Include,
+}
+
+/// One entry of a sysctl file, as yielded by `Entries`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub kind: EntryKind,
+    pub key_path: Vec<String>,
+    /// Empty for `EntryKind::Exclude`.
+    pub value: String,
+    /// The line started with `-`, so errors about it are to be ignored.
+    pub ignore_errors: bool,
+    pub line: usize,
+    /// 1-based column where the key starts.
+    pub column: usize,
+    pub text: String,
+    /// The inline comment after the value, without the `#` or `;`. Only set with
+    /// `ParseOptions::inline_comments`.
+    pub comment: Option<String>,
+}
+
+// An `Entry` whose key, value and text may borrow from the input, as read by `Lexer`.
+struct EntryRef<'a> {
+    kind: EntryKind,
+    key_path: Vec<Cow<'a, str>>,
+    value: Cow<'a, str>,
+    ignore_errors: bool,
+    line: usize,
+    column: usize,
+    text: Cow<'a, str>,
+    comment: Option<Cow<'a, str>>,
+}
+
+impl EntryRef<'_> {
+    fn into_owned(self) -> Entry {
+        Entry {
+            kind: self.kind,
+            key_path: self.key_path.into_iter().map(Cow::into_owned).collect(),
+            value: self.value.into_owned(),
+            ignore_errors: self.ignore_errors,
+            line: self.line,
+            column: self.column,
+            text: self.text.into_owned(),
+            comment: self.comment.map(Cow::into_owned),
+        }
+    }
+
+    // The same entry, no longer borrowing from the line it was read from.
+    fn into_static(self) -> EntryRef<'static> {
+        let entry = self.into_owned();
+        EntryRef {
+            kind: entry.kind,
+            key_path: entry.key_path.into_iter().map(Cow::Owned).collect(),
+            value: Cow::Owned(entry.value),
+            ignore_errors: entry.ignore_errors,
+            line: entry.line,
+            column: entry.column,
+            text: Cow::Owned(entry.text),
+            comment: entry.comment.map(Cow::Owned),
+        }
+    }
+}
+
+/// Reads a sysctl file one entry at a time without building a `SysctlConfig`.
+///
+/// Comments and blank lines are skipped, and so are invalid lines starting with `-`. Other
+/// invalid lines yield an `Err` wrapping a `ParseError`, after which iteration can continue.
+///
+/// A UTF-8 byte order mark at the start of the input is skipped and lines may end in `\n` or
+/// `\r\n`. A line that is not valid UTF-8 is an error with `ParseErrorKind::InvalidUtf8`, or
+/// decoded with replacement characters under `ParseOptions::lossy_utf8`.
+pub struct Entries<R> {
+    lexer: Lexer<ReadLines<R>>,
+}
+
+impl<R: BufRead> Entries<R> {
+    pub fn new(reader: R) -> Self {
+        Self::with_path(reader, None)
+    }
+
+    /// Like `new`, with `path` reported in errors.
+    pub fn with_path(reader: R, path: Option<&Path>) -> Self {
+        Self::with_options(reader, path, &ParseOptions::default())
+    }
+
+    pub(crate) fn with_options(reader: R, path: Option<&Path>, options: &ParseOptions) -> Self {
+        Self {
+            lexer: Lexer::new(ReadLines::new(reader), path, options),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Entries<R> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.lexer.next_entry()?.map(EntryRef::into_owned))
+    }
+}
+
+// Where `Lexer` reads physical lines from: a reader, or a string that entries can borrow from.
+trait LineSource<'a> {
+    // The 1-based number and text of the next physical line, without its line ending.
+    fn next_line(
+        &mut self,
+        path: Option<&Path>,
+        options: &ParseOptions,
+    ) -> Option<Result<(usize, Cow<'a, str>)>>;
+}
+
+struct ReadLines<R> {
+    reader: R,
+    lineno: usize,
+    offset: usize,
+    // Set once `ParseOptions::max_file_size` is exceeded, to stop reading.
+    exhausted: bool,
+}
+
+impl<R> ReadLines<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            lineno: 0,
+            offset: 0,
+            exhausted: false,
+        }
+    }
+}
+
+impl<R: BufRead> LineSource<'static> for ReadLines<R> {
+    fn next_line(
+        &mut self,
+        path: Option<&Path>,
+        options: &ParseOptions,
+    ) -> Option<Result<(usize, Cow<'static, str>)>> {
+        if self.exhausted {
+            return None;
+        }
+
+        // Reads at most a byte past the size limits, so that an oversized line or file is never
+        // held in memory whole. The line ending and a BOM on the first line don't count.
+        let file_left = options
+            .max_file_size
+            .saturating_sub(self.offset)
+            .saturating_add(1);
+        let bom_len = if self.offset == 0 { 3 } else { 0 };
+        let limit = options
+            .max_line_length
+            .saturating_add(2 + bom_len)
+            .min(file_left) as u64;
+        let mut buf = vec![];
+        match Read::take(&mut self.reader, limit).read_until(b'\n', &mut buf) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(e) => return Some(Err(e.into())),
+        }
+        let start = self.offset;
+        self.offset += buf.len();
+        self.lineno += 1;
+        if !buf.ends_with(b"\n") && buf.len() as u64 == limit {
+            let file_left = options
+                .max_file_size
+                .saturating_sub(self.offset)
+                .saturating_add(1);
+            match Read::take(&mut self.reader, file_left as u64).skip_until(b'\n') {
+                Ok(skipped) => self.offset += skipped,
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+
+        let (bom, bytes) = match trim_line(&buf, start, self.offset, self.lineno, path, options) {
+            Ok(trimmed) => trimmed,
+            Err(e) => {
+                self.exhausted = matches!(e.kind, ParseErrorKind::FileTooLarge { .. });
+                return Some(Err(e.into()));
+            }
+        };
+        match std::str::from_utf8(bytes) {
+            Ok(line) => Some(Ok((self.lineno, Cow::Owned(line.to_string())))),
+            Err(_) if options.lossy_utf8 => Some(Ok((
+                self.lineno,
+                Cow::Owned(String::from_utf8_lossy(bytes).into_owned()),
+            ))),
+            Err(e) => {
+                let valid = String::from_utf8_lossy(&bytes[..e.valid_up_to()]);
+                Some(Err(ParseError {
+                    path: path.map(Path::to_path_buf),
+                    line: self.lineno,
+                    column: valid.chars().count() + 1,
+                    text: String::from_utf8_lossy(bytes).into_owned(),
+                    kind: ParseErrorKind::InvalidUtf8 {
+                        offset: start + bom + e.valid_up_to(),
+                    },
+                    include_chain: vec![],
+                }
+                .into()))
+            }
+        }
+    }
+}
+
+struct StrLines<'a> {
+    s: &'a str,
+    lineno: usize,
+    offset: usize,
+    // Set once `ParseOptions::max_file_size` is exceeded, to stop reading.
+    exhausted: bool,
+}
+
+impl<'a> StrLines<'a> {
+    fn new(s: &'a str) -> Self {
+        Self {
+            s,
+            lineno: 0,
+            offset: 0,
+            exhausted: false,
+        }
+    }
+}
+
+impl<'a> LineSource<'a> for StrLines<'a> {
+    fn next_line(
+        &mut self,
+        path: Option<&Path>,
+        options: &ParseOptions,
+    ) -> Option<Result<(usize, Cow<'a, str>)>> {
+        if self.exhausted || self.offset == self.s.len() {
+            return None;
+        }
+
+        let rest = &self.s[self.offset..];
+        let len = rest.find('\n').map_or(rest.len(), |i| i + 1);
+        let start = self.offset;
+        self.offset += len;
+        self.lineno += 1;
+        match trim_line(
+            &rest.as_bytes()[..len],
+            start,
+            self.offset,
+            self.lineno,
+            path,
+            options,
+        ) {
+            Ok((bom, bytes)) => Some(Ok((
+                self.lineno,
+                Cow::Borrowed(&rest[bom..bom + bytes.len()]),
+            ))),
+            Err(e) => {
+                self.exhausted = matches!(e.kind, ParseErrorKind::FileTooLarge { .. });
+                Some(Err(e.into()))
+            }
+        }
+    }
+}
+
+// Strips the line ending from the physical line `buf`, which starts at byte `start` of the
+// input and ends at `end`, and a byte order mark if it is the first line. Returns the length of
+// the byte order mark and the rest of the line, or an error if the line exceeds a size limit.
+fn trim_line<'b>(
+    mut buf: &'b [u8],
+    start: usize,
+    end: usize,
+    lineno: usize,
+    path: Option<&Path>,
+    options: &ParseOptions,
+) -> Result<(usize, &'b [u8]), ParseError> {
+    if let Some(line) = buf.strip_suffix(b"\n") {
+        buf = line;
+    }
+    if let Some(line) = buf.strip_suffix(b"\r") {
+        buf = line;
+    }
+    let bom = if start == 0 && buf.starts_with(b"\xEF\xBB\xBF") {
+        3
+    } else {
+        0
+    };
+
+    let bytes = &buf[bom..];
+    match options.exceeded_size(bytes, start + bom, end) {
+        Some((kind, at)) => Err(size_error(kind, bytes, at, path, lineno)),
+        None => Ok((bom, bytes)),
+    }
+}
+
+// Turns physical lines into entries, following `[section]` headers and joining continued lines.
+// Entries of a `StrLines` borrow from the string where they can.
+struct Lexer<L> {
+    lines: L,
+    // The key prefix set by the last `[section]` header, or `None` after an invalid header.
+    section: Option<Vec<String>>,
+    path: Option<PathBuf>,
+    options: ParseOptions,
+}
+
+impl<L> Lexer<L> {
+    fn new(lines: L, path: Option<&Path>, options: &ParseOptions) -> Self {
+        Self {
+            lines,
+            section: Some(vec![]),
+            path: path.map(Path::to_path_buf),
+            options: options.clone(),
+        }
+    }
+
+    fn next_entry<'a>(&mut self) -> Option<Result<EntryRef<'a>>>
+    where
+        L: LineSource<'a>,
+    {
+        let path = self.path.as_deref();
+        loop {
+            let (lineno, line) = match self.lines.next_line(path, &self.options)? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e)),
+            };
+            match section_of(&line, &self.options) {
+                Some(Ok(section)) => {
+                    self.section = Some(section);
+                    continue;
+                }
+                Some(Err(e)) => {
+                    // Entries below an invalid header would end up under the wrong prefix.
+                    self.section = None;
+                    return Some(Err(e.into_parse_error(&line, path, lineno).into()));
+                }
+                None => {}
+            }
+            let entry = if continues(&line, &self.options) {
+                let mut joined = ContinuedLine::new(lineno, &line);
+                while joined.more {
+                    match self.lines.next_line(path, &self.options) {
+                        Some(Ok((lineno, line))) => joined.push(lineno, &line),
+                        Some(Err(e)) => return Some(Err(e)),
+                        None => break,
+                    }
+                }
+                joined.entry(path, &self.options)
+            } else {
+                match line {
+                    Cow::Borrowed(line) => entry_of_line(line, path, lineno, &self.options),
+                    Cow::Owned(line) => entry_of_line(&line, path, lineno, &self.options)
+                        .map(|entry| entry.map(EntryRef::into_static)),
+                }
+            };
+            match (entry, &self.section) {
+                (Ok(Some(entry)), _) if entry.kind == EntryKind::Include => return Some(Ok(entry)),
+                (Ok(Some(mut entry)), Some(section)) => {
+                    entry
+                        .key_path
+                        .splice(0..0, section.iter().cloned().map(Cow::Owned));
+                    return Some(Ok(entry));
+                }
+                (Ok(_), _) => continue,
+                (Err(e), _) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
+
+// The error for a line that exceeds a size limit at byte `at`. Only the part of the line up to
+// the limit is kept as its text.
+fn size_error(
+    kind: ParseErrorKind,
+    line: &[u8],
+    at: usize,
+    path: Option<&Path>,
+    lineno: usize,
+) -> ParseError {
+    let text = String::from_utf8_lossy(&line[..at]).into_owned();
+    ParseError {
+        path: path.map(Path::to_path_buf),
+        line: lineno,
+        column: text.chars().count() + 1,
+        text,
+        kind,
+        include_chain: vec![],
+    }
+}
+
+// The key prefix set by a `[section]` header, or `None` if `line` is not a header. `[]` goes
+// back to no prefix.
+fn section_of(line: &str, options: &ParseOptions) -> Option<Result<Vec<String>, LexError>> {
+    let header = line.trim();
+    if !options.sections || !header.starts_with('[') {
+        return None;
+    }
+
+    let start = line.len() - line.trim_start().len();
+    let error = |kind: ParseErrorKind, at: usize| {
+        Some(Err(LexError {
+            kind,
+            offset: start + at,
+            ignore_error: false,
+        }))
+    };
+    let Some(end) = header.find(']') else {
+        return error(ParseErrorKind::InvalidSection, header.len());
+    };
+    if end + 1 < header.len() {
+        return error(ParseErrorKind::InvalidSection, end + 1);
+    }
+    let name = header[1..end].trim();
+    if let Some(ws) = name.find(char::is_whitespace) {
+        let leading = header[1..end].len() - header[1..end].trim_start().len();
+        return error(ParseErrorKind::WhitespaceInKey, 1 + leading + ws);
+    }
+    if name.is_empty() {
+        return Some(Ok(vec![]));
+    }
+    Some(Ok(split_key_with(name, options)
+        .into_iter()
+        .map(Cow::into_owned)
+        .collect()))
+}
+
+fn continues(line: &str, options: &ParseOptions) -> bool {
+    let start = if options.indented_comments {
+        line.trim_start()
+    } else {
+        line
+    };
+    options.line_continuation
+        && line.ends_with('\\')
+        && !(start.starts_with('#') || (options.semicolon_comments && start.starts_with(';')))
+}
+
+// Physical lines joined into one logical line by trailing backslashes. `parts` holds the line
+// number and text of each physical line and the offset in `text` where it starts, so that
+// errors can point at the physical line.
+struct ContinuedLine {
+    text: String,
+    parts: Vec<(usize, String, usize)>,
+    more: bool,
+}
+
+impl ContinuedLine {
+    fn new(lineno: usize, line: &str) -> Self {
+        let mut joined = Self {
+            text: String::new(),
+            parts: vec![],
+            more: true,
+        };
+        joined.push(lineno, line);
+        joined
+    }
+
+    fn push(&mut self, lineno: usize, line: &str) {
+        self.parts.push((lineno, line.to_string(), self.text.len()));
+        match line.strip_suffix('\\') {
+            Some(line) => self.text.push_str(line),
+            None => self.text.push_str(line),
+        }
+        self.more = line.ends_with('\\');
+    }
+
+    fn entry(
+        &self,
+        path: Option<&Path>,
+        options: &ParseOptions,
+    ) -> Result<Option<EntryRef<'static>>, ParseError> {
+        match entry_of_line(&self.text, path, self.parts[0].0, options) {
+            Ok(entry) => Ok(entry.map(EntryRef::into_static)),
+            Err(e) => Err(self.locate(e)),
+        }
+    }
+
+    fn locate(&self, e: ParseError) -> ParseError {
+        let offset = self
+            .text
+            .char_indices()
+            .nth(e.column - 1)
+            .map_or(self.text.len(), |(i, _)| i);
+        let (lineno, text, start) = self
+            .parts
+            .iter()
+            .rev()
+            .find(|(_, _, start)| *start <= offset)
+            .unwrap_or(&self.parts[0]);
+        let at = (offset - start).min(text.len());
+        ParseError {
+            line: *lineno,
+            column: column_of(text, at),
+            text: text.clone(),
+            ..e
+        }
+    }
+}
+
+/// Splits a key into its path components, following systemd-sysctl: if the first separator
+/// is `/`, components are separated by `/` and dots are literal
+/// (`net/ipv4/conf/eth0.100/rp_filter`). Otherwise components are separated by `.` and a `/` stands for a literal dot
+/// (`net.ipv4.conf.eth0/100.rp_filter`).
+pub fn split_key(key: &str) -> Vec<Cow<'_, str>> {
+    match key.find(['.', '/']) {
+        Some(i) if key.as_bytes()[i] == b'/' => key.split('/').map(Cow::Borrowed).collect(),
+        _ => key
+            .split('.')
+            .map(|c| {
+                if c.contains('/') {
+                    Cow::Owned(c.replace('/', "."))
+                } else {
+                    Cow::Borrowed(c)
+                }
+            })
+            .collect(),
+    }
+}
+
+// `split_key`, or a split on dots only if `options.slash_separator` is off.
+fn split_key_with<'a>(key: &'a str, options: &ParseOptions) -> Vec<Cow<'a, str>> {
+    if options.slash_separator {
+        split_key(key)
+    } else {
+        key.split('.').map(Cow::Borrowed).collect()
+    }
+}
+
+// `join_key`, or a join on dots only if `options.slash_separator` is off, so that the key reads
+// back as `keys` with `options`.
+fn join_key_with<S: AsRef<str>>(keys: &[S], options: &ParseOptions) -> String {
+    if options.slash_separator {
+        join_key(keys)
+    } else {
+        keys.iter()
+            .map(AsRef::as_ref)
+            .collect::<Vec<&str>>()
+            .join(".")
+    }
+}
+
+/// Joins path components back into a key. Uses the `.` form unless a component contains a dot,
+/// in which case the `/` form is used so that the component stays readable.
+pub fn join_key<S: AsRef<str>>(components: &[S]) -> String {
+    let components = components.iter().map(AsRef::as_ref).collect::<Vec<&str>>();
+    if components.iter().any(|c| c.contains('.')) {
+        components.join("/")
+    } else {
+        components.join(".")
+    }
+}
+
+// Converts a byte offset in `line` into a 1-based column.
+fn column_of(line: &str, offset: usize) -> usize {
+    line[..offset].chars().count() + 1
+}
+
+// One physical line of a sysctl file, classified without touching the config. Shared by
+// `Entries` and `Document` so both accept exactly the same syntax.
+enum Line<'a> {
+    Blank,
+    Comment,
+    Entry(EntryLine<'a>),
+    // `-key` without a value, with `options.globs`: excludes `key` from glob expansion.
+    Exclusion(&'a str),
+    // `!key`, with `options.operators`.
+    Unset(&'a str),
+}
+
+// Offsets are byte offsets into the whole line, including the `-` prefix.
+struct EntryLine<'a> {
+    ignore_error: bool,
+    // `key += value`, with `options.operators`.
+    append: bool,
+    key: &'a str,
+    key_start: usize,
+    value: &'a str,
+    value_start: usize,
+}
+
+struct LexError {
+    kind: ParseErrorKind,
+    offset: usize,
+    ignore_error: bool,
+}
+
+impl LexError {
+    fn into_parse_error(self, line: &str, path: Option<&Path>, lineno: usize) -> ParseError {
+        ParseError {
+            path: path.map(Path::to_path_buf),
+            line: lineno,
+            column: column_of(line, self.offset),
+            text: line.to_string(),
+            kind: self.kind,
+            include_chain: vec![],
+        }
+    }
+}
+
+fn lex_line<'a>(line: &'a str, options: &ParseOptions) -> Result<Line<'a>, LexError> {
+    let start = if options.indented_comments {
+        line.trim_start()
+    } else {
+        line
+    };
+    if start.is_empty() {
+        return Ok(Line::Blank);
+    }
+
+    if start.starts_with('#') || (options.semicolon_comments && start.starts_with(';')) {
+        return Ok(Line::Comment);
+    }
+
+    let (body, offset, ignore_error) = match line
+        .strip_prefix('-')
+        .filter(|_| options.ignore_error_prefix)
+    {
+        Some(body) => (body, 1, true),
+        None => (line, 0, false),
+    };
+    let error = |kind: ParseErrorKind, at: usize| LexError {
+        kind,
+        offset: offset + at,
+        ignore_error,
+    };
+
+    let leading = body.len() - body.trim_start().len();
+    let Some(eq) = body.find('=') else {
+        let key = body.trim();
+        if ignore_error && options.globs && !key.is_empty() && !key.contains(char::is_whitespace) {
+            return Ok(Line::Exclusion(key));
+        }
+        if let Some(unset) = key.strip_prefix('!').filter(|_| options.operators) {
+            let unset = unset.trim_start();
+            if unset.is_empty() {
+                return Err(error(ParseErrorKind::EmptyKey, leading + 1));
+            }
+            if let Some(ws) = unset
+                .find(char::is_whitespace)
+                .filter(|_| !options.whitespace_in_keys)
+            {
+                return Err(error(
+                    ParseErrorKind::WhitespaceInKey,
+                    leading + (key.len() - unset.len()) + ws,
+                ));
+            }
+            return Ok(Line::Unset(unset));
+        }
+        return Err(error(ParseErrorKind::MissingDelimiter, leading));
+    };
+    let key = body[..eq].trim();
+    let (key, append) = match key.strip_suffix('+').filter(|_| options.operators) {
+        Some(key) => (key.trim_end(), true),
+        None => (key, false),
+    };
+    let raw_value = &body[eq + 1..];
+    let value = raw_value.trim();
+    if key.is_empty() {
+        return Err(error(ParseErrorKind::EmptyKey, eq));
+    }
+    if value.is_empty() && !options.empty_values {
+        return Err(error(ParseErrorKind::EmptyValue, eq + 1));
+    }
+    if let Some(ws) = key
+        .find(char::is_whitespace)
+        .filter(|_| !options.whitespace_in_keys)
+    {
+        return Err(error(ParseErrorKind::WhitespaceInKey, leading + ws));
+    }
+
+    Ok(Line::Entry(EntryLine {
+        ignore_error,
+        append,
+        key,
+        key_start: offset + leading,
+        value,
+        value_start: offset + eq + 1 + (raw_value.len() - raw_value.trim_start().len()),
+    }))
+}
+
+// Strips the quotes from a quoted value and resolves its escapes, if `options.quoted_values` is
+// set. On error, returns the byte offset of the problem within `value`.
+fn decode_value<'a>(
+    value: &'a str,
+    options: &ParseOptions,
+) -> Result<Cow<'a, str>, (ParseErrorKind, usize)> {
+    let quote = match value.chars().next() {
+        Some(c @ ('"' | '\'')) if options.quoted_values => c,
+        _ => return Ok(Cow::Borrowed(value)),
+    };
+
+    let body = &value[1..];
+    let mut decoded = String::new();
+    let mut escaped = false;
+    let mut chars = body.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                escaped = true;
+                match chars.next() {
+                    Some((_, 'n')) => decoded.push('\n'),
+                    Some((_, 't')) => decoded.push('\t'),
+                    Some((_, c @ ('\\' | '"' | '\''))) => decoded.push(c),
+                    Some(_) => return Err((ParseErrorKind::InvalidEscape, 1 + i)),
+                    None => break,
+                }
+            }
+            c if c == quote => {
+                if i + 1 < body.len() {
+                    return Err((ParseErrorKind::TrailingCharacters, 1 + i + 1));
+                }
+                return Ok(if escaped {
+                    Cow::Owned(decoded)
+                } else {
+                    Cow::Borrowed(&body[..i])
+                });
+            }
+            c => decoded.push(c),
+        }
+    }
+    Err((ParseErrorKind::UnterminatedQuote, 0))
+}
+
+// Splits an inline comment off `value` if `options.inline_comments` is set. A `#` or `;` starts
+// the comment when it begins the value or follows whitespace, and is never part of a comment
+// inside a quoted value.
+fn strip_comment<'a>(value: &'a str, options: &ParseOptions) -> (&'a str, Option<&'a str>) {
+    if !options.inline_comments {
+        return (value, None);
+    }
+
+    let mut start = 0;
+    if let Some(quote @ ('"' | '\'')) = value.chars().next().filter(|_| options.quoted_values) {
+        let mut escaped = false;
+        start = value.len();
+        for (i, c) in value.char_indices().skip(1) {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                c if c == quote => {
+                    start = i + 1;
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let comment = value[start..]
+        .char_indices()
+        .map(|(i, c)| (start + i, c))
+        .find(|&(i, c)| {
+            (c == '#' || (c == ';' && options.semicolon_comments))
+                && (i == 0 || value[..i].ends_with(char::is_whitespace))
+        });
+    match comment {
+        Some((i, _)) => (value[..i].trim_end(), Some(value[i + 1..].trim())),
+        None => (value, None),
+    }
+}
+
+// The decoded value of `entry` and its inline comment.
+fn value_of<'a>(
+    entry: &EntryLine<'a>,
+    options: &ParseOptions,
+) -> Result<(Cow<'a, str>, Option<&'a str>), LexError> {
+    let error = |kind: ParseErrorKind, at: usize| LexError {
+        kind,
+        offset: entry.value_start + at,
+        ignore_error: entry.ignore_error,
+    };
+    let (value, comment) = strip_comment(entry.value, options);
+    if value.is_empty() && !options.empty_values {
+        return Err(error(ParseErrorKind::EmptyValue, 0));
+    }
+    match decode_value(value, options) {
+        Ok(value) => Ok((value, comment)),
+        Err((kind, at)) => Err(error(kind, at)),
+    }
+}
+
+// The pattern of an `include pattern` line. A line with `=` is always an assignment, so a key
+// may still be named `include`.
+fn include_of(line: &str) -> Option<&str> {
+    let rest = line.trim_start().strip_prefix("include")?;
+    let pattern = rest.trim();
+    (rest.starts_with(char::is_whitespace) && !pattern.is_empty() && !line.contains('='))
+        .then_some(pattern)
+}
+
+fn entry_of_line<'a>(
+    line: &'a str,
+    path: Option<&Path>,
+    lineno: usize,
+    options: &ParseOptions,
+) -> Result<Option<EntryRef<'a>>, ParseError> {
+    let entry = |kind: EntryKind,
+                 key: &'a str,
+                 value: Cow<'a, str>,
+                 ignore_errors: bool,
+                 key_start: usize| EntryRef {
+        kind,
+        key_path: split_key_with(key, options),
+        value,
+        ignore_errors,
+        line: lineno,
+        column: column_of(line, key_start),
+        text: Cow::Borrowed(line),
+        comment: None,
+    };
+
+    if let Some(pattern) = include_of(line).filter(|_| options.includes) {
+        let start = line.len() - line.trim_start().len();
+        return Ok(Some(EntryRef {
+            key_path: vec![],
+            ..entry(EntryKind::Include, "", Cow::Borrowed(pattern), false, start)
+        }));
+    }
+
+    match lex_line(line, options) {
+        Ok(Line::Entry(e)) => match value_of(&e, options) {
+            Ok((value, comment)) => Ok(Some(EntryRef {
+                comment: comment.map(Cow::Borrowed),
+                ..entry(
+                    if e.append {
+                        EntryKind::Append
+                    } else {
+                        EntryKind::Assign
+                    },
+                    e.key,
+                    value,
+                    e.ignore_error,
+                    e.key_start,
+                )
+            })),
+            Err(_) if e.ignore_error => Ok(None),
+            Err(e) => Err(e.into_parse_error(line, path, lineno)),
+        },
+        Ok(Line::Exclusion(key)) => {
+            let key_start = line.find(key).unwrap_or(0);
+            Ok(Some(entry(
+                EntryKind::Exclude,
+                key,
+                Cow::Borrowed(""),
+                true,
+                key_start,
+            )))
+        }
+        Ok(Line::Unset(key)) => {
+            let key_start = line.find(key).unwrap_or(0);
+            Ok(Some(entry(
+                EntryKind::Unset,
+                key,
+                Cow::Borrowed(""),
+                false,
+                key_start,
+            )))
+        }
+        Ok(_) => Ok(None),
+        Err(e) if e.ignore_error => Ok(None),
+        Err(e) => Err(e.into_parse_error(line, path, lineno)),
+    }
+}
+
+fn insert_entry<'a, C: Table<'a>>(
+    report: &mut ParseReport<C>,
+    mut entry: EntryRef<'a>,
+    path: Option<&Path>,
+    options: &ParseOptions,
+) -> Result<(), ParseError> {
+    if let Some(source) = &options.variables {
+        let mut unresolved = vec![];
+        if let Cow::Owned(value) = interpolate::expand(&entry.value, source, &mut unresolved) {
+            entry.value = Cow::Owned(value);
+        }
+        if !entry.ignore_errors {
+            report
+                .unresolved
+                .extend(unresolved.into_iter().map(|name| UnresolvedVariable {
+                    name,
+                    origin: Origin {
+                        path: path.map(Path::to_path_buf),
+                        line: entry.line,
+                    },
+                }));
+        }
+    }
+
+    let error_or_ignore = |kind: ParseErrorKind| {
+        if entry.ignore_errors {
+            return Ok(());
+        }
+        Err(ParseError {
+            path: path.map(Path::to_path_buf),
+            line: entry.line,
+            column: entry.column,
+            text: entry.text.to_string(),
+            kind,
+            include_chain: vec![],
+        })
+    };
+
+    // Everything but another value for a key that has one adds a key.
+    let adds_key = matches!(entry.kind, EntryKind::Exclude | EntryKind::Unset)
+        || !matches!(report.config.slot_at(&entry.key_path), Some(Slot::Leaf(..)));
+    if let Some(kind) = options.exceeded_limit(
+        entry.key_path.len(),
+        entry.value.len(),
+        adds_key,
+        report.keys,
+    ) {
+        return error_or_ignore(kind);
+    }
+
+    let map = &mut report.config;
+    if entry.kind == EntryKind::Exclude {
+        map.push_exclusion(entry.key_path);
+        report.keys += 1;
+        return Ok(());
+    }
+
+    // `+=` and `!` take effect here. A `!` is also kept for a later merge, and so is a `+=` with
+    // nothing to append to.
+    let origin = Origin {
+        path: path.map(Path::to_path_buf),
+        line: entry.line,
+    };
+    if matches!(entry.kind, EntryKind::Append | EntryKind::Unset) && map.operations_mut().is_none()
+    {
+        return error_or_ignore(ParseErrorKind::UnsupportedOperator);
+    }
+    if entry.kind == EntryKind::Unset {
+        map.remove_path(&entry.key_path);
+        if let Some(operations) = map.operations_mut() {
+            operations.push(Operation {
+                key_path: entry.key_path.into_iter().map(Cow::into_owned).collect(),
+                kind: OperationKind::Unset,
+                origin,
+            });
+        }
+        report.keys += 1;
+        return Ok(());
+    }
+    if entry.kind == EntryKind::Append {
+        match map.slot_at(&entry.key_path) {
+            Some(Slot::Leaf(old, _)) => {
+                entry.value = Cow::Owned(operation::append_field(old, &entry.value))
+            }
+            // A table here, or a value above it: `insert_path` reports the conflict.
+            Some(Slot::Table(_)) => {}
+            None if (1..entry.key_path.len())
+                .any(|n| matches!(map.slot_at(&entry.key_path[..n]), Some(Slot::Leaf(..)))) => {}
+            None => {
+                if let Some(operations) = map.operations_mut() {
+                    operations.push(Operation {
+                        key_path: entry.key_path.into_iter().map(Cow::into_owned).collect(),
+                        kind: OperationKind::Append(entry.value.into_owned()),
+                        origin,
+                    });
+                }
+                report.keys += 1;
+                return Ok(());
+            }
+        }
+    }
+
+    if options.globs && entry.key_path.iter().any(|k| glob::is_glob(k)) {
+        map.push_glob(entry.key_path, entry.value, origin);
+        report.keys += 1;
+        return Ok(());
+    }
+
+    if options.deny_duplicates && entry.kind == EntryKind::Assign {
+        if let Some(Slot::Leaf(_, other)) = map.slot_at(&entry.key_path) {
+            return error_or_ignore(ParseErrorKind::DuplicateKey {
+                other: Box::new(other.clone()),
+            });
+        }
+    }
+
+    match map.insert_path(
+        &entry.key_path,
+        entry.value.clone(),
+        origin.clone(),
+        entry.comment,
+        options.conflict_policy,
+    ) {
+        Ok(replaced) => {
+            // `ConflictPolicy::FirstWins` may have skipped the line.
+            if adds_key && matches!(map.slot_at(&entry.key_path), Some(Slot::Leaf(..))) {
+                report.keys += 1;
+            }
+            if let (Some((old_value, old)), EntryKind::Assign) = (replaced, entry.kind) {
+                report.overrides.push(Override {
+                    key_path: entry.key_path.into_iter().map(Cow::into_owned).collect(),
+                    old_value: old_value.into_owned(),
+                    new_value: entry.value.into_owned(),
+                    old,
+                    new: origin,
+                });
+            }
+        }
+        Err(conflict) => {
+            return error_or_ignore(ParseErrorKind::LeafBranchConflict {
+                other_key: conflict.key,
+                other: Box::new(conflict.origin),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    // The table at `key` of `map`, failing the test if there is none.
+    pub(crate) fn table<'m>(map: &'m SysctlConfig, key: &str) -> &'m SysctlConfig {
+        match map.get(key) {
+            Some(SysctlConfigValue::SysctlConfig(m)) => m,
+            other => panic!("expected a table at {:?}, but got {:?}", key, other),
+        }
+    }
+
+    // The value at `key` of `map`, failing the test if there is none.
+    pub(crate) fn value<'m>(map: &'m SysctlConfig, key: &str) -> &'m str {
+        match map.get(key) {
+            Some(SysctlConfigValue::String(v)) => v,
+            other => panic!("expected a value at {:?}, but got {:?}", key, other),
+        }
+    }
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn ok() {
+        let test_data = "hoge = fuga
+piyo = moge
+";
+
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(test_data.as_bytes()).unwrap();
+
+        let map = load_sysctl(f.path().to_str().unwrap().to_string()).unwrap();
+
+        let hoge = map.get("hoge").unwrap();
+        if let SysctlConfigValue::String(v) = hoge {
+            assert_eq!(v, "fuga");
+        } else {
+            panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "hoge");
+        }
+
+        let piyo = map.get("piyo").unwrap();
+        if let SysctlConfigValue::String(v) = piyo {
+            assert_eq!(v, "moge");
+        } else {
+            panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "piyo");
+        }
+    }
+
+    #[test]
+    fn ok_with_nested() {
+        let test_data = "foo.bar = bar
+foo.baz = baz
+bar.baz = foo
+";
+
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(test_data.as_bytes()).unwrap();
+
+        let map = load_sysctl(f.path().to_str().unwrap().to_string()).unwrap();
+
+        let foo = map.get("foo").unwrap();
+        if let SysctlConfigValue::SysctlConfig(v) = foo {
+            let bar = v.get("bar").unwrap();
+            if let SysctlConfigValue::String(v) = bar {
+                assert_eq!(v, "bar");
+            } else {
+                panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "foo.bar");
+            }
+
+            let baz = v.get("baz").unwrap();
+            if let SysctlConfigValue::String(v) = baz {
+                assert_eq!(v, "baz");
+            } else {
+                panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "foo.baz");
+            }
+        } else {
+            panic!("expected SysctlConfigValue::SysctlConfig, but got SysctlConfigValue::String: key={}", "foo");
+        }
+
+        let bar = map.get("bar").unwrap();
+        if let SysctlConfigValue::SysctlConfig(v) = bar {
+            let baz = v.get("baz").unwrap();
+            if let SysctlConfigValue::String(v) = baz {
+                assert_eq!(v, "foo");
+            } else {
+                panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "bar.baz");
+            }
+        } else {
+            panic!("expected SysctlConfigValue::SysctlConfig, but got SysctlConfigValue::String: key={}", "bar");
+        }
+    }
+
+    #[test]
+    fn ok_with_comment() {
+        let test_data = "# foo = bar
+bar = baz
+";
+
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(test_data.as_bytes()).unwrap();
+
+        let map = load_sysctl(f.path().to_str().unwrap().to_string()).unwrap();
+
+        let foo = map.get("foo");
+        assert!(foo.is_none());
+
+        let bar = map.get("bar").unwrap();
+        if let SysctlConfigValue::String(v) = bar {
+            assert_eq!(v, "baz");
+        } else {
+            panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "bar");
+        }
+    }
+
+    #[test]
+    fn ok_with_ignore_error() {
+        let test_data = "- foobar
+- foo = bar
+";
+
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(test_data.as_bytes()).unwrap();
+
+        let map = load_sysctl(f.path().to_str().unwrap().to_string()).unwrap();
+
+        let foo = map.get("foo").unwrap();
+        if let SysctlConfigValue::String(v) = foo {
+            assert_eq!(v, "bar");
+        } else {
+            panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "foo");
+        }
+    }
+
+    #[test]
+    fn ok_with_empty_line() {
+        let test_data = "foo = bar
+
+baz = qux
+";
+
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(test_data.as_bytes()).unwrap();
+
+        let map = load_sysctl(f.path().to_str().unwrap().to_string()).unwrap();
+
+        let foo = map.get("foo").unwrap();
+        if let SysctlConfigValue::String(v) = foo {
+            assert_eq!(v, "bar");
+        } else {
+            panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "foo");
+        }
+
+        let baz = map.get("baz").unwrap();
+        if let SysctlConfigValue::String(v) = baz {
+            assert_eq!(v, "qux");
+        } else {
+            panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "baz");
+        }
+    }
+
+    #[test]
+    fn ng_with_no_delimiter() {
+        let test_data = "foo
+";
+
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(test_data.as_bytes()).unwrap();
+
+        let map = load_sysctl(f.path().to_str().unwrap().to_string());
+        let err = map.unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::MissingDelimiter);
+    }
+
+    #[test]
+    fn ng_with_zero_length_key() {
+        let test_data = " = foo
+";
+
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(test_data.as_bytes()).unwrap();
+
+        let map = load_sysctl(f.path().to_str().unwrap().to_string());
+        let err = map.unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::EmptyKey);
+    }
+
+    #[test]
+    fn ng_with_zero_length_value() {
+        let test_data = "foo =
+";
+
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(test_data.as_bytes()).unwrap();
+
+        let map = load_sysctl(f.path().to_str().unwrap().to_string());
+        let err = map.unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::EmptyValue);
+    }
+
+    #[test]
+    fn ng_with_whitespace_key() {
+        let test_data = "foo bar = baz
+";
+
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(test_data.as_bytes()).unwrap();
+
+        let map = load_sysctl(f.path().to_str().unwrap().to_string());
+        let err = map.unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::WhitespaceInKey);
+    }
+
+    #[test]
+    fn ng_with_leaf_branch_conflict() {
+        let test_data = "foo = bar
+foo.baz = qux
+";
+
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(test_data.as_bytes()).unwrap();
+
+        let map = load_sysctl(f.path().to_str().unwrap().to_string());
+        let err = map.unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::LeafBranchConflict {
+                other_key: "foo".to_string(),
+                other: Box::new(Origin {
+                    path: Some(f.path().to_path_buf()),
+                    line: 1
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn ng_error_position() {
+        let test_data = "# comment
+foo = bar
+
+-  ignored
+  foo bar = baz
+";
+
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(test_data.as_bytes()).unwrap();
+
+        let map = load_sysctl(f.path().to_str().unwrap().to_string());
+        let err = map.unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::WhitespaceInKey);
+        assert_eq!(err.path.as_deref(), Some(f.path()));
+        assert_eq!(err.line, 5);
+        assert_eq!(err.column, 6);
+        assert_eq!(err.text, "  foo bar = baz");
+    }
+
+    #[test]
+    fn ok_recovering() {
+        let test_data = "foo = bar
+baz
+qux = 
+hoge = fuga
+";
+
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(test_data.as_bytes()).unwrap();
+
+        let report = load_sysctl_recovering(f.path().to_str().unwrap().to_string()).unwrap();
+
+        assert!(report.has_errors());
+        let errors = report
+            .errors
+            .iter()
+            .map(|e| (e.line, e.kind.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                (2, ParseErrorKind::MissingDelimiter),
+                (3, ParseErrorKind::EmptyValue),
+            ]
+        );
+
+        assert_eq!(value(&report.config, "foo"), "bar");
+        assert_eq!(value(&report.config, "hoge"), "fuga");
+    }
+
+    #[test]
+    fn ok_recovering_without_error() {
+        let test_data = "foo = bar
+";
+
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(test_data.as_bytes()).unwrap();
+
+        let report = load_sysctl_recovering(f.path().to_str().unwrap().to_string()).unwrap();
+        assert!(!report.has_errors());
+        assert!(report.into_result().is_ok());
+    }
+
+    #[test]
+    fn ok_parse_str() {
+        let map = parse_str("foo.bar = baz\n# comment\nqux = quux\n").unwrap();
+
+        assert_eq!(value(table(&map, "foo"), "bar"), "baz");
+        assert_eq!(value(&map, "qux"), "quux");
+    }
+
+    #[test]
+    fn ok_parse_reader() {
+        let r = std::io::Cursor::new(b"foo = bar\n".to_vec());
+        let map = parse_reader(r).unwrap();
+        assert_eq!(value(&map, "foo"), "bar");
+    }
+
+    #[test]
+    fn ok_load_path() {
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(b"foo = bar\n").unwrap();
+
+        let map = load_path(f.path()).unwrap();
+        assert!(map.contains_key("foo"));
+    }
+
+    #[test]
+    fn ng_parse_str() {
+        let err = parse_str("foo = bar\nbaz\n").unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::MissingDelimiter);
+        assert_eq!(err.path, None);
+        assert_eq!(err.line, 2);
+    }
+
+    #[test]
+    fn ok_preserves_order() {
+        let test_data = "net.ipv4.conf.all.rp_filter = 1
+kernel.sysrq = 0
+net.ipv4.conf.eth0.rp_filter = 2
+net.core.somaxconn = 1024
+abi.vsyscall32 = 1
+";
+
+        let map = parse_str(test_data).unwrap();
+        assert_eq!(map.keys().collect::<Vec<_>>(), vec!["net", "kernel", "abi"]);
+
+        let net = table(&map, "net");
+        assert_eq!(net.keys().collect::<Vec<_>>(), vec!["ipv4", "core"]);
+    }
+
+    #[test]
+    fn ok_entry_origin() {
+        let test_data = "# comment
+net.ipv4.ip_forward = 1
+
+kernel.sysrq = 0
+";
+
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(test_data.as_bytes()).unwrap();
+
+        let map = load_path(f.path()).unwrap();
+
+        let origin = map.entry_origin("net.ipv4.ip_forward").unwrap();
+        assert_eq!(origin.path.as_deref(), Some(f.path()));
+        assert_eq!(origin.line, 2);
+
+        let origin = map.entry_origin("kernel.sysrq").unwrap();
+        assert_eq!(origin.line, 4);
+
+        assert!(map.entry_origin("net.ipv4").is_none());
+        assert!(map.entry_origin("net.ipv6.ip_forward").is_none());
+    }
+
+    #[test]
+    fn ok_split_key() {
+        assert_eq!(
+            split_key("net.ipv4.ip_forward"),
+            vec!["net", "ipv4", "ip_forward"]
+        );
+        assert_eq!(
+            split_key("net/ipv4/conf/eth0.100/rp_filter"),
+            vec!["net", "ipv4", "conf", "eth0.100", "rp_filter"]
+        );
+        assert_eq!(
+            split_key("net.ipv4.conf.eth0/100.rp_filter"),
+            vec!["net", "ipv4", "conf", "eth0.100", "rp_filter"]
+        );
+        assert_eq!(split_key("foo"), vec!["foo"]);
+
+        assert_eq!(
+            join_key(&["net", "ipv4", "ip_forward"]),
+            "net.ipv4.ip_forward"
+        );
+        assert_eq!(
+            join_key(&["net", "ipv4", "conf", "eth0.100", "rp_filter"]),
+            "net/ipv4/conf/eth0.100/rp_filter"
+        );
+    }
+
+    #[test]
+    fn ok_with_slash_separator() {
+        let test_data = "net/ipv4/conf/eth0.100/rp_filter = 2
+net.ipv4.conf.eth0/100.accept_redirects = 0
+net/ipv4/ip_forward = 1
+";
+
+        let map = parse_str(test_data).unwrap();
+
+        let net = table(&map, "net");
+        let ipv4 = table(net, "ipv4");
+        let conf = table(ipv4, "conf");
+        assert_eq!(conf.keys().collect::<Vec<_>>(), vec!["eth0.100"]);
+        let vlan = table(conf, "eth0.100");
+        assert_eq!(
+            vlan.keys().collect::<Vec<_>>(),
+            vec!["rp_filter", "accept_redirects"]
+        );
+
+        let v = value(ipv4, "ip_forward");
+        assert_eq!(v, "1");
+
+        assert_eq!(
+            map.entry_origin("net/ipv4/conf/eth0.100/rp_filter")
+                .unwrap()
+                .line,
+            1
+        );
+        assert_eq!(
+            map.entry_origin("net.ipv4.conf.eth0/100.accept_redirects")
+                .unwrap()
+                .line,
+            2
+        );
+    }
+
+    #[test]
+    fn ok_with_glob() {
+        let test_data = "net.ipv4.conf.*.rp_filter = 2
+-net.ipv4.conf.lo.rp_filter
+net.ipv4.conf.eth1.rp_filter = 0
+net.ipv4.conf.eth*.accept_redirects = 1
+net.ipv4.conf.eth0.accept_redirects = 0
+";
 
-pub fn load_sysctl(path: String) -> Result<SysctlConfig> {
-    let file = std::fs::read_to_string(path)?;
-    let r = BufReader::new(Cursor::new(file));
-    let r = BufReader::new(r);
-    load_sysctl_from_reader(r)
-}
+        let map = parse_str(test_data).unwrap();
+        assert_eq!(
+            map.globs()
+                .iter()
+                .map(|g| (join_key(&g.key_path), g.value.as_str(), g.origin.line))
+                .collect::<Vec<_>>(),
+            vec![
+                ("net.ipv4.conf.*.rp_filter".to_string(), "2", 1),
+                ("net.ipv4.conf.eth*.accept_redirects".to_string(), "1", 4),
+            ]
+        );
+        assert_eq!(
+            map.exclusions(),
+            [vec!["net", "ipv4", "conf", "lo", "rp_filter"]]
+        );
 
-fn load_sysctl_from_reader<T: Read>(reader: BufReader<T>) -> Result<SysctlConfig> {
-    let mut map = SysctlConfig::new();
-    for line in reader.lines() {
-        let line = line?;
-        insert_entry_of_line(&mut map, line)?;
+        let universe = KeyUniverse::from_keys([
+            "net.ipv4.conf.all.rp_filter",
+            "net.ipv4.conf.lo.rp_filter",
+            "net.ipv4.conf.eth0.rp_filter",
+            "net.ipv4.conf.eth1.rp_filter",
+            "net/ipv4/conf/eth0.100/rp_filter",
+            "net.ipv4.conf.lo.accept_redirects",
+            "net.ipv4.conf.eth0.accept_redirects",
+            "net.ipv4.conf.eth1.accept_redirects",
+        ]);
+        let expanded = map.expand_globs(&universe);
+        assert!(expanded.globs().is_empty());
+        assert!(expanded.exclusions().is_empty());
+
+        let net = table(&expanded, "net");
+        let ipv4 = table(net, "ipv4");
+        let conf = table(ipv4, "conf");
+
+        let value_of = |interface: &str, key: &str| match conf.get(interface) {
+            Some(SysctlConfigValue::SysctlConfig(m)) => match m.get(key) {
+                Some(SysctlConfigValue::String(v)) => Some(v.clone()),
+                _ => None,
+            },
+            _ => None,
+        };
+        assert_eq!(value_of("all", "rp_filter").as_deref(), Some("2"));
+        assert_eq!(value_of("lo", "rp_filter"), None);
+        assert_eq!(value_of("eth0", "rp_filter").as_deref(), Some("2"));
+        assert_eq!(value_of("eth1", "rp_filter").as_deref(), Some("0"));
+        assert_eq!(value_of("eth0.100", "rp_filter").as_deref(), Some("2"));
+        assert_eq!(value_of("lo", "accept_redirects"), None);
+        assert_eq!(value_of("eth0", "accept_redirects").as_deref(), Some("0"));
+        assert_eq!(value_of("eth1", "accept_redirects").as_deref(), Some("1"));
+
+        assert_eq!(
+            expanded
+                .entry_origin("net.ipv4.conf.all.rp_filter")
+                .unwrap()
+                .line,
+            1
+        );
+        assert_eq!(
+            expanded
+                .entry_origin("net.ipv4.conf.eth1.rp_filter")
+                .unwrap()
+                .line,
+            3
+        );
     }
-    Ok(map)
-}
 
-fn insert_entry_of_line<'a>(map: &mut SysctlConfig, line: String) -> Result<()> {
-    let mut line = line;
-    if line.is_empty() {
-        return Ok(())
+    #[test]
+    fn ok_expand_globs_without_slash_separator() {
+        let map = ParseOptions::new()
+            .slash_separator(false)
+            .parse_str("kern.a/b* = 1\nkern.x* = 2\n-kern.xz\n")
+            .unwrap();
+        assert_eq!(map.globs()[0].key_path, ["kern", "a/b*"]);
+
+        // `a/b*` is a single component here, so it doesn't match the component `a.bc`.
+        let universe = KeyUniverse::from_keys(["kern.a/bc", "kern.xy", "kern.xz"]);
+        let expanded = map.expand_globs(&universe);
+        let kern = table(&expanded, "kern");
+        assert_eq!(kern.keys().collect::<Vec<_>>(), vec!["xy"]);
     }
 
-    if line.starts_with("#") || line.starts_with(";") {
-        return Ok(())
+    #[test]
+    fn ok_expand_globs_with_proc_root() {
+        let root = tempfile::tempdir().unwrap();
+        for key in ["kernel/sysrq", "kernel/panic", "vm/swappiness"] {
+            let path = root.path().join(key);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "0\n").unwrap();
+        }
+
+        let map = parse_str("kernel.* = 1\n").unwrap();
+        let expanded = map.expand_globs(&KeyUniverse::from_proc_root(root.path()).unwrap());
+
+        let kernel = table(&expanded, "kernel");
+        assert_eq!(kernel.keys().collect::<Vec<_>>(), vec!["panic", "sysrq"]);
+        assert!(expanded.get("vm").is_none());
     }
 
-    let error_or_ignore = if line.starts_with("-") {
-        line.remove(0);
-        |_| Ok(())
-    } else {
-        |s| Err(Error::msg(s))
-    };
-    
-    let parts: Vec<&str> = line.splitn(2, '=').collect();
-    if parts.len() != 2 {
-        return error_or_ignore("invalid line");
+    #[test]
+    fn ok_merge() {
+        let mut map = parse_str("foo.bar = 1\nfoo.baz = 2\nqux = 3\nkernel.* = 0\n").unwrap();
+        map.merge(parse_str("foo.baz = 20\nqux.quux = 30\nhoge = 40\n-kernel.sysrq\n").unwrap());
+
+        assert_eq!(map.keys().collect::<Vec<_>>(), vec!["foo", "qux", "hoge"]);
+        let foo = table(&map, "foo");
+        let v = value(foo, "bar");
+        assert_eq!(v, "1");
+        let v = value(foo, "baz");
+        assert_eq!(v, "20");
+        assert_eq!(map.entry_origin("foo.baz").unwrap().line, 1);
+
+        assert!(matches!(
+            map.get("qux"),
+            Some(SysctlConfigValue::SysctlConfig(_))
+        ));
+        assert!(map.entry_origin("qux").is_none());
+        assert_eq!(map.globs().len(), 1);
+        assert_eq!(map.exclusions(), [vec!["kernel", "sysrq"]]);
     }
-    let key = parts[0].trim();
-    let value = parts[1].trim();
-    if key.is_empty() || value.is_empty() || key.contains(' ') {
-        return error_or_ignore("invalid line");
+
+    #[test]
+    fn ng_with_branch_leaf_conflict() {
+        let err = parse_str("foo.bar.baz = 1\nfoo.qux = 2\nfoo = 3\n").unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.line, 3);
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::LeafBranchConflict {
+                other_key: "foo.bar.baz".to_string(),
+                other: Box::new(Origin {
+                    path: None,
+                    line: 1
+                }),
+            }
+        );
+        assert_eq!(err.to_string(), "3:1: key is used both as a value and as a table, conflicting with foo.bar.baz at line 1: \"foo = 3\"");
     }
 
-    let keys = key.split('.').collect::<Vec<&str>>();
+    #[test]
+    fn ok_conflict_policy_last_wins() {
+        let options = ParseOptions::new().conflict_policy(ConflictPolicy::LastWins);
+
+        let map = options.parse_str("foo = 1\nfoo.bar = 2\n").unwrap();
+        let foo = table(&map, "foo");
+        assert_eq!(foo.keys().collect::<Vec<_>>(), vec!["bar"]);
+        assert!(map.entry_origin("foo").is_none());
 
-    let mut m = map;
-    for i in 0..keys.len() {
-        let key = keys[i];
-        if i == keys.len() - 1 {
-            m.insert(key.to_string(), SysctlConfigValue::String(value.to_string()));
-        } else {
-            let next_m = m.entry(key.to_string()).or_insert_with(|| SysctlConfigValue::SysctlConfig(SysctlConfig::new()));
-            if let SysctlConfigValue::SysctlConfig(next_m) = next_m {
-                m = next_m;
-            } else {
-                return error_or_ignore("invalid line");
-            }
-        }
+        let map = options.parse_str("foo.bar = 2\nfoo = 1\n").unwrap();
+        let v = value(&map, "foo");
+        assert_eq!(v, "1");
+        assert_eq!(map.entry_origin("foo").unwrap().line, 2);
     }
 
-    Ok(())
-}
+    #[test]
+    fn ok_conflict_policy_first_wins() {
+        let options = ParseOptions::new().conflict_policy(ConflictPolicy::FirstWins);
 
-#[cfg(test)]
-mod tests {
-    use std::io::Write;
+        let map = options.parse_str("foo = 1\nfoo.bar = 2\n").unwrap();
+        let v = value(&map, "foo");
+        assert_eq!(v, "1");
 
-    use tempfile::NamedTempFile;
-    use super::*;
+        let map = options.parse_str("foo.bar = 2\nfoo = 1\n").unwrap();
+        let foo = table(&map, "foo");
+        assert_eq!(foo.keys().collect::<Vec<_>>(), vec!["bar"]);
+    }
 
     #[test]
-    fn ok() {
-        let test_data =
-"hoge = fuga
-piyo = moge
+    fn ok_overrides() {
+        let test_data = "vm.swappiness = 60
+kernel.sysrq = 0
+vm.swappiness = 10
 ";
 
-        let mut f = NamedTempFile::new().unwrap();
-        f.write_all(test_data.as_bytes()).unwrap();
+        let report = ParseOptions::new()
+            .parse_reader_recovering(test_data.as_bytes())
+            .unwrap();
+        assert!(!report.has_errors());
+        assert_eq!(
+            report.overrides,
+            vec![Override {
+                key_path: vec!["vm".to_string(), "swappiness".to_string()],
+                old_value: "60".to_string(),
+                new_value: "10".to_string(),
+                old: Origin {
+                    path: None,
+                    line: 1
+                },
+                new: Origin {
+                    path: None,
+                    line: 3
+                },
+            }]
+        );
+        assert_eq!(
+            report.overrides[0].to_string(),
+            "line 3: vm.swappiness = \"10\" overrides \"60\" assigned at line 1"
+        );
 
-        let map = load_sysctl(f.path().to_str().unwrap().to_string()).unwrap();
+        let vm = table(&report.config, "vm");
+        let v = value(vm, "swappiness");
+        assert_eq!(v, "10");
+    }
 
-        let hoge = map.get("hoge").unwrap();
-        if let SysctlConfigValue::String(v) = hoge {
-            assert_eq!(v, "fuga");
-        } else {
-            panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "hoge");
-        }
+    #[test]
+    fn ng_deny_duplicates() {
+        let test_data = "vm.swappiness = 60
+vm/swappiness = 10
+";
 
-        let piyo = map.get("piyo").unwrap();
-        if let SysctlConfigValue::String(v) = piyo {
-            assert_eq!(v, "moge");
-        } else {
-            panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "piyo");
-        }
+        let options = ParseOptions::new().deny_duplicates(true);
+        let err = options.parse_str(test_data).unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.line, 2);
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::DuplicateKey {
+                other: Box::new(Origin {
+                    path: None,
+                    line: 1
+                })
+            }
+        );
+
+        let map = options
+            .parse_str("-vm.swappiness = 60\n-vm.swappiness = 10\n")
+            .unwrap();
+        assert_eq!(map.entry_origin("vm.swappiness").unwrap().line, 1);
     }
 
     #[test]
-    fn ok_with_nested() {
-        let test_data =
-"foo.bar = bar
-foo.baz = baz
-bar.baz = foo
+    fn ok_entries() {
+        let test_data = "# comment
+net/ipv4/conf/eth0.100/rp_filter = 2
+- broken line
+-net.ipv4.conf.lo.rp_filter
+kernel.sysrq=0
 ";
 
-        let mut f = NamedTempFile::new().unwrap();
-        f.write_all(test_data.as_bytes()).unwrap();
+        let entries = Entries::new(test_data.as_bytes())
+            .collect::<Result<Vec<Entry>>>()
+            .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                Entry {
+                    kind: EntryKind::Assign,
+                    key_path: vec![
+                        "net".to_string(),
+                        "ipv4".to_string(),
+                        "conf".to_string(),
+                        "eth0.100".to_string(),
+                        "rp_filter".to_string()
+                    ],
+                    value: "2".to_string(),
+                    ignore_errors: false,
+                    line: 2,
+                    column: 1,
+                    text: "net/ipv4/conf/eth0.100/rp_filter = 2".to_string(),
+                    comment: None,
+                },
+                Entry {
+                    kind: EntryKind::Exclude,
+                    key_path: vec![
+                        "net".to_string(),
+                        "ipv4".to_string(),
+                        "conf".to_string(),
+                        "lo".to_string(),
+                        "rp_filter".to_string()
+                    ],
+                    value: "".to_string(),
+                    ignore_errors: true,
+                    line: 4,
+                    column: 2,
+                    text: "-net.ipv4.conf.lo.rp_filter".to_string(),
+                    comment: None,
+                },
+                Entry {
+                    kind: EntryKind::Assign,
+                    key_path: vec!["kernel".to_string(), "sysrq".to_string()],
+                    value: "0".to_string(),
+                    ignore_errors: false,
+                    line: 5,
+                    column: 1,
+                    text: "kernel.sysrq=0".to_string(),
+                    comment: None,
+                },
+            ]
+        );
+    }
 
-        let map = load_sysctl(f.path().to_str().unwrap().to_string()).unwrap();
+    #[test]
+    fn ok_entries_continue_after_error() {
+        let mut entries = Entries::new("foo\nbar = baz\n".as_bytes());
 
-        let foo = map.get("foo").unwrap();
-        if let SysctlConfigValue::SysctlConfig(v) = foo {
-            let bar = v.get("bar").unwrap();
-            if let SysctlConfigValue::String(v) = bar {
-                assert_eq!(v, "bar");
-            } else {
-                panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "foo.bar");
-            }
+        let err = entries.next().unwrap().unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::MissingDelimiter);
 
-            let baz = v.get("baz").unwrap();
-            if let SysctlConfigValue::String(v) = baz {
-                assert_eq!(v, "baz");
-            } else {
-                panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "foo.baz");
-            }
+        let entry = entries.next().unwrap().unwrap();
+        assert_eq!(entry.key_path, vec!["bar"]);
+        assert_eq!(entry.value, "baz");
 
-        } else {
-            panic!("expected SysctlConfigValue::SysctlConfig, but got SysctlConfigValue::String: key={}", "foo");
-        }
+        assert!(entries.next().is_none());
+    }
 
-        let bar = map.get("bar").unwrap();
-        if let SysctlConfigValue::SysctlConfig(v) = bar {
-            let baz = v.get("baz").unwrap();
-            if let SysctlConfigValue::String(v) = baz {
-                assert_eq!(v, "foo");
-            } else {
-                panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "bar.baz");
-            }
-        } else {
-            panic!("expected SysctlConfigValue::SysctlConfig, but got SysctlConfigValue::String: key={}", "bar");
+    #[test]
+    fn ok_quoted_values() {
+        let test_data = r#"kernel.core_pattern = "|/usr/bin/dump %p # not a comment"
+leading = '  padded  '
+empty = ""
+escaped = "a\tb\n\"c\" \\ \'d\'"
+"#;
+
+        let map = ParseOptions::new()
+            .quoted_values(true)
+            .parse_str(test_data)
+            .unwrap();
+        let value_of = |key: &str| value(&map, key);
+        assert_eq!(value_of("leading"), "  padded  ");
+        assert_eq!(value_of("empty"), "");
+        assert_eq!(value_of("escaped"), "a\tb\n\"c\" \\ 'd'");
+
+        let kernel = table(&map, "kernel");
+        let v = value(kernel, "core_pattern");
+        assert_eq!(v, "|/usr/bin/dump %p # not a comment");
+    }
+
+    #[test]
+    fn ok_quoted_values_disabled() {
+        let map = parse_str("foo = \"bar\"\n").unwrap();
+        let v = value(&map, "foo");
+        assert_eq!(v, "\"bar\"");
+    }
+
+    #[test]
+    fn ng_quoted_values() {
+        let options = ParseOptions::new().quoted_values(true);
+        for (test_data, kind, column) in [
+            ("foo = \"bar\n", ParseErrorKind::UnterminatedQuote, 7),
+            ("foo = 'bar\\'\n", ParseErrorKind::UnterminatedQuote, 7),
+            ("foo = \"b\\ar\"\n", ParseErrorKind::InvalidEscape, 9),
+            (
+                "foo = \"bar\" baz\n",
+                ParseErrorKind::TrailingCharacters,
+                12,
+            ),
+        ] {
+            let err = options.parse_str(test_data).unwrap_err();
+            let err = err.downcast_ref::<ParseError>().unwrap();
+            assert_eq!((&err.kind, err.column), (&kind, column), "{:?}", test_data);
         }
+
+        assert!(options.parse_str("-foo = \"bar\n").unwrap().is_empty());
     }
 
     #[test]
-    fn ok_with_comment() {
-        let test_data =
-"# foo = bar
-bar = baz
+    fn ok_as_fields() {
+        let test_data = "net.ipv4.ip_local_port_range = 1024	65000
+net.ipv4.tcp_rmem = 4096  87380 6291456
 ";
 
-        let mut f = NamedTempFile::new().unwrap();
-        f.write_all(test_data.as_bytes()).unwrap();
+        let map = parse_str(test_data).unwrap();
+        let net = table(&map, "net");
+        let ipv4 = table(net, "ipv4");
+        assert_eq!(
+            ipv4.get("ip_local_port_range").unwrap().as_fields(),
+            Some(vec!["1024", "65000"])
+        );
+        assert_eq!(
+            ipv4.get("tcp_rmem").unwrap().as_fields(),
+            Some(vec!["4096", "87380", "6291456"])
+        );
+        assert_eq!(net.get("ipv4").unwrap().as_fields(), None);
+    }
 
-        let map = load_sysctl(f.path().to_str().unwrap().to_string()).unwrap();
+    #[test]
+    fn ok_eq_ignores_field_whitespace() {
+        assert_eq!(
+            SysctlConfigValue::String("1024\t65000".to_string()),
+            SysctlConfigValue::String("1024 65000".to_string())
+        );
+        assert_ne!(
+            SysctlConfigValue::String("1024 65000".to_string()),
+            SysctlConfigValue::String("1024 65001".to_string())
+        );
+        assert_ne!(
+            SysctlConfigValue::String("1024".to_string()),
+            SysctlConfigValue::SysctlConfig(SysctlConfig::new())
+        );
 
-        let foo = map.get("foo");
-        assert!(foo.is_none());
+        let a =
+            parse_str("net.ipv4.ip_local_port_range = 1024\t65000\nkernel.sysrq = 0\n").unwrap();
+        let b =
+            parse_str("\nkernel.sysrq = 0\nnet.ipv4.ip_local_port_range = 1024   65000\n").unwrap();
+        let c = parse_str("kernel.sysrq = 1\nnet.ipv4.ip_local_port_range = 1024 65000\n").unwrap();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
 
-        let bar = map.get("bar").unwrap();
-        if let SysctlConfigValue::String(v) = bar {
-            assert_eq!(v, "baz");
-        } else {
-            panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "bar");
-        }
+    #[test]
+    fn ok_line_continuation() {
+        let test_data = "kernel.core_pattern = |/usr/lib/systemd/systemd-coredump \\
+%P %u %g %s %t %c %h
+vm.swappiness = 10
+# comment \\
+kernel.sysrq = 0
+";
+
+        let options = ParseOptions::new().line_continuation(true);
+        let map = options.parse_str(test_data).unwrap();
+        let kernel = table(&map, "kernel");
+        let v = value(kernel, "core_pattern");
+        assert_eq!(v, "|/usr/lib/systemd/systemd-coredump %P %u %g %s %t %c %h");
+        assert!(kernel.contains_key("sysrq"));
+        assert_eq!(map.entry_origin("kernel.core_pattern").unwrap().line, 1);
+        assert_eq!(map.entry_origin("vm.swappiness").unwrap().line, 3);
+
+        let map = parse_str("foo = bar \\\n").unwrap();
+        let v = value(&map, "foo");
+        assert_eq!(v, "bar \\");
     }
 
     #[test]
-    fn ok_with_ignore_error() {
-        let test_data =
-"- foobar
-- foo = bar
+    fn ng_line_continuation() {
+        let test_data = "foo = bar
+baz = \\
+   \"qux\\
+   quux
 ";
 
-        let mut f = NamedTempFile::new().unwrap();
-        f.write_all(test_data.as_bytes()).unwrap();
+        let options = ParseOptions::new()
+            .line_continuation(true)
+            .quoted_values(true);
+        let err = options.parse_str(test_data).unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::UnterminatedQuote);
+        assert_eq!(err.line, 3);
+        assert_eq!(err.column, 4);
+        assert_eq!(err.text, "   \"qux\\");
 
-        let map = load_sysctl(f.path().to_str().unwrap().to_string()).unwrap();
+        let err = options
+            .parse_str("foo = bar\nbaz \\\n  qux = 1\n")
+            .unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::WhitespaceInKey);
+        assert_eq!((err.line, err.column), (2, 4));
+    }
 
-        let foo = map.get("foo").unwrap();
-        if let SysctlConfigValue::String(v) = foo {
-            assert_eq!(v, "bar");
-        } else {
-            panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "foo");
-        }
+    #[test]
+    fn ok_inline_comments() {
+        let test_data = r#"vm.swappiness = 10  # tuned for DB hosts
+kernel.core_pattern = |/usr/bin/dump %p;%e ; dump helper
+kernel.hostname = "db#1" ; quoted
+net.ipv4.tcp_rmem = 4096 87380 6291456
+"#;
+
+        let options = ParseOptions::new()
+            .inline_comments(true)
+            .quoted_values(true);
+        let map = options.parse_str(test_data).unwrap();
+        let vm = table(&map, "vm");
+        let v = value(vm, "swappiness");
+        assert_eq!(v, "10");
+        assert_eq!(
+            map.entry_comment("vm.swappiness"),
+            Some("tuned for DB hosts")
+        );
+
+        let kernel = table(&map, "kernel");
+        let v = value(kernel, "core_pattern");
+        assert_eq!(v, "|/usr/bin/dump %p;%e");
+        assert_eq!(
+            map.entry_comment("kernel.core_pattern"),
+            Some("dump helper")
+        );
+        let v = value(kernel, "hostname");
+        assert_eq!(v, "db#1");
+        assert_eq!(map.entry_comment("kernel.hostname"), Some("quoted"));
+        assert_eq!(map.entry_comment("net.ipv4.tcp_rmem"), None);
+
+        let entry = options
+            .entries("foo = 1 # one\n".as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.value, "1");
+        assert_eq!(entry.comment.as_deref(), Some("one"));
     }
 
     #[test]
-    fn ok_with_empty_line() {
-        let test_data =
-"foo = bar
+    fn ok_inline_comments_disabled() {
+        let map = parse_str("vm.swappiness = 10  # tuned for DB hosts\n").unwrap();
+        let vm = table(&map, "vm");
+        let v = value(vm, "swappiness");
+        assert_eq!(v, "10  # tuned for DB hosts");
+        assert_eq!(map.entry_comment("vm.swappiness"), None);
+    }
 
-baz = qux
+    #[test]
+    fn ok_inline_comments_override() {
+        let options = ParseOptions::new().inline_comments(true);
+        let map = options.parse_str("foo = 1 # first\nfoo = 2\n").unwrap();
+        assert_eq!(map.entry_comment("foo"), None);
+
+        let mut map = options.parse_str("foo = 1 # first\nbar = 2\n").unwrap();
+        map.merge(options.parse_str("bar = 3 # second\n").unwrap());
+        assert_eq!(map.entry_comment("foo"), Some("first"));
+        assert_eq!(map.entry_comment("bar"), Some("second"));
+    }
+
+    #[test]
+    fn ng_inline_comments() {
+        let err = ParseOptions::new()
+            .inline_comments(true)
+            .parse_str("foo = # nothing\n")
+            .unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::EmptyValue);
+        assert_eq!(err.column, 7);
+    }
+
+    #[test]
+    fn ok_variables() {
+        let test_data = "kernel.core_pattern = ${LOG_DIR}/core.%p
+fs.suid_dumpable = ${SUID_DUMPABLE:-0}
+net.ipv4.conf.*.rp_filter = ${RP_FILTER}
 ";
 
-        let mut f = NamedTempFile::new().unwrap();
-        f.write_all(test_data.as_bytes()).unwrap();
+        let vars = HashMap::from([
+            ("LOG_DIR".to_string(), "/var/log".to_string()),
+            ("RP_FILTER".to_string(), "2".to_string()),
+        ]);
+        let map = ParseOptions::new()
+            .variables(vars)
+            .parse_str(test_data)
+            .unwrap();
+        let kernel = table(&map, "kernel");
+        let v = value(kernel, "core_pattern");
+        assert_eq!(v, "/var/log/core.%p");
+        let fs = table(&map, "fs");
+        let v = value(fs, "suid_dumpable");
+        assert_eq!(v, "0");
+        assert_eq!(map.globs()[0].value, "2");
 
-        let map = load_sysctl(f.path().to_str().unwrap().to_string()).unwrap();
+        let map = ParseOptions::new()
+            .variables(VariableSource::Env)
+            .parse_str("foo = ${PATH:-unset}\n")
+            .unwrap();
+        let v = value(&map, "foo");
+        assert_eq!(*v, std::env::var("PATH").unwrap_or("unset".to_string()));
 
-        let foo = map.get("foo").unwrap();
-        if let SysctlConfigValue::String(v) = foo {
-            assert_eq!(v, "bar");
-        } else {
-            panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "foo");
-        }
+        let map = parse_str("foo = ${LOG_DIR}\n").unwrap();
+        let v = value(&map, "foo");
+        assert_eq!(v, "${LOG_DIR}");
+    }
 
-        let baz = map.get("baz").unwrap();
-        if let SysctlConfigValue::String(v) = baz {
-            assert_eq!(v, "qux");
-        } else {
-            panic!("expected SysctlConfigValue::String, but got SysctlConfigValue::SysctlConfig: key={}", "baz");
-        }
+    #[test]
+    fn ng_variables() {
+        let test_data = "foo = ${LOG_DIR}/app.log
+-bar = ${IGNORED}
+baz = ${A}:${B:-b}:${C}
+";
+
+        let options = ParseOptions::new().variables(HashMap::new());
+        let err = options.parse_str(test_data).unwrap_err();
+        let err = err.downcast_ref::<UnresolvedVariables>().unwrap();
+        let unresolved = err
+            .variables
+            .iter()
+            .map(|v| (v.name.as_str(), v.origin.line))
+            .collect::<Vec<_>>();
+        assert_eq!(unresolved, vec![("LOG_DIR", 1), ("A", 3), ("C", 3)]);
+        assert_eq!(
+            err.to_string(),
+            "unresolved variables: LOG_DIR at line 1, A at line 3, C at line 3"
+        );
+
+        let report = options
+            .parse_reader_recovering(test_data.as_bytes())
+            .unwrap();
+        assert!(report.has_errors());
+        assert!(report.errors.is_empty());
+        assert_eq!(report.unresolved.len(), 3);
     }
 
     #[test]
-    fn ng_with_no_delimiter() {
-        let test_data =
-"foo
+    fn ok_bom_and_crlf() {
+        let test_data = "\u{FEFF}kernel.sysrq = 1\r\nvm.swappiness = 10\r\n";
+        let map = parse_str(test_data).unwrap();
+        assert_eq!(map.keys().collect::<Vec<_>>(), vec!["kernel", "vm"]);
+        assert_eq!(map, parse_borrowed(test_data).unwrap().to_owned());
+
+        let entries = ParseOptions::new()
+            .entries(test_data.as_bytes())
+            .collect::<Result<Vec<Entry>>>()
+            .unwrap();
+        assert_eq!(entries[0].text, "kernel.sysrq = 1");
+        assert_eq!(entries[1].text, "vm.swappiness = 10");
+    }
+
+    #[test]
+    fn ng_invalid_utf8() {
+        let test_data = b"foo = 1\nbar = caf\xe9\nbaz = 3\n";
+
+        let err = parse_reader(&test_data[..]).unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::InvalidUtf8 { offset: 17 });
+        assert_eq!((err.line, err.column), (2, 10));
+        assert_eq!(err.text, "bar = caf\u{FFFD}");
+
+        let report = ParseOptions::new()
+            .parse_reader_recovering(&test_data[..])
+            .unwrap();
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.config.contains_key("baz"));
+    }
+
+    #[test]
+    fn ok_lossy_utf8() {
+        let map = ParseOptions::new()
+            .lossy_utf8(true)
+            .parse_reader(&b"bar = caf\xe9\r\n"[..])
+            .unwrap();
+        let v = value(&map, "bar");
+        assert_eq!(v, "caf\u{FFFD}");
+    }
+
+    #[test]
+    fn ok_parse_options_rules() {
+        let test_data = "  # indented comment
+    
+kernel.domainname =
+foo bar = 1
 ";
 
-        let mut f = NamedTempFile::new().unwrap();
-        f.write_all(test_data.as_bytes()).unwrap();
+        let err = parse_str(test_data).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>().unwrap().kind,
+            ParseErrorKind::MissingDelimiter
+        );
 
-        let map = load_sysctl(f.path().to_str().unwrap().to_string());
-        assert!(map.is_err());
+        let options = ParseOptions::new().indented_comments(true);
+        let err = options.parse_str(test_data).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>().unwrap().kind,
+            ParseErrorKind::EmptyValue
+        );
+
+        let options = options.empty_values(true);
+        let err = options.parse_str(test_data).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>().unwrap().kind,
+            ParseErrorKind::WhitespaceInKey
+        );
+
+        let map = options
+            .whitespace_in_keys(true)
+            .parse_str(test_data)
+            .unwrap();
+        let kernel = table(&map, "kernel");
+        let v = value(kernel, "domainname");
+        assert_eq!(v, "");
+        assert!(map.contains_key("foo bar"));
     }
 
     #[test]
-    fn ng_with_zero_length_key() {
-        let test_data =
-" = foo
+    fn ok_ignore_error_prefix_disabled() {
+        let options = ParseOptions::new().ignore_error_prefix(false);
+        let map = options.parse_str("-foo = 1\n").unwrap();
+        assert!(map.contains_key("-foo"));
+
+        let err = options.parse_str("-foo bar = 1\n").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>().unwrap().kind,
+            ParseErrorKind::WhitespaceInKey
+        );
+    }
+
+    #[test]
+    fn ok_parse_options_presets() {
+        let test_data = "  ; comment\nkernel.domainname =\nkernel = 1\n";
+
+        let err = ParseOptions::procps().parse_str(test_data).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>().unwrap().kind,
+            ParseErrorKind::EmptyValue
+        );
+
+        let map = ParseOptions::systemd().parse_str(test_data).unwrap();
+        assert_eq!(
+            map.get("kernel"),
+            Some(&SysctlConfigValue::String("1".to_string()))
+        );
+
+        let err = ParseOptions::strict()
+            .parse_str("foo = 1\nfoo = 2\n")
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ParseError>().unwrap().kind,
+            ParseErrorKind::DuplicateKey { .. }
+        ));
+    }
+
+    #[test]
+    fn ok_dot_only_keys() {
+        let options = ParseOptions::freebsd()
+            .inline_comments(true)
+            .operators(true);
+        let map = options.parse_str("a.b/c=1 # one\n").unwrap();
+        assert_eq!(
+            map.get_path(&["a", "b/c"]),
+            Some(&SysctlConfigValue::String("1".to_string()))
+        );
+        assert_eq!(map.entry_origin("a.b/c").unwrap().line, 1);
+        assert_eq!(map.entry_comment("a.b/c"), Some("one"));
+
+        let err = options
+            .clone()
+            .deny_duplicates(true)
+            .parse_str("a.b/c=1\na.b/c=2\n")
+            .unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::DuplicateKey {
+                other: Box::new(Origin {
+                    path: None,
+                    line: 1
+                })
+            }
+        );
+
+        let report = options
+            .parse_reader_recovering("a.b/c=1\na.b/c=2\n".as_bytes())
+            .unwrap();
+        assert_eq!(report.overrides[0].key_path, ["a", "b/c"]);
+
+        let mut map = options.parse_str("a.b/c=1\nd.e/f=2\n").unwrap();
+        map.merge(options.parse_str("!a.b/c\nd.e/f+=3\n").unwrap());
+        assert_eq!(
+            map.leaves(),
+            vec![(vec!["d".to_string(), "e/f".to_string()], "2 3".to_string())]
+        );
+    }
+
+    #[test]
+    fn ok_load_sysctl_with_options() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"  # indented\nfoo = 1\n").unwrap();
+        let path = file.path().to_str().unwrap().to_string();
+
+        assert!(load_sysctl(path.clone()).is_err());
+        let map = load_sysctl_with_options(path, &ParseOptions::procps()).unwrap();
+        assert!(map.contains_key("foo"));
+    }
+
+    #[test]
+    fn ok_sections() {
+        let test_data = "endpoint = https://example.com
+[log]
+file = /var/log/app.log
+level = info
+
+[net.ipv4]
+ip_forward = 1
+conf/eth0.100/rp_filter = 2
+conf.*.accept_redirects = 0
+-conf.lo.accept_redirects
+[net.ipv4.neigh.*]
+gc_stale_time = 60
+[]
+kernel.sysrq = 16
 ";
 
-        let mut f = NamedTempFile::new().unwrap();
-        f.write_all(test_data.as_bytes()).unwrap();
+        let qualified = "endpoint = https://example.com
+log.file = /var/log/app.log
+log.level = info
+net.ipv4.ip_forward = 1
+net/ipv4/conf/eth0.100/rp_filter = 2
+net.ipv4.conf.*.accept_redirects = 0
+-net.ipv4.conf.lo.accept_redirects
+net.ipv4.neigh.*.gc_stale_time = 60
+kernel.sysrq = 16
+";
 
-        let map = load_sysctl(f.path().to_str().unwrap().to_string());
-        assert!(map.is_err());
+        let options = ParseOptions::new().sections(true);
+        let map = options.parse_str(test_data).unwrap();
+        assert_eq!(map, parse_str(qualified).unwrap());
+        assert_eq!(
+            map.keys().collect::<Vec<_>>(),
+            vec!["endpoint", "log", "net", "kernel"]
+        );
+        assert_eq!(map.entry_origin("log.file").unwrap().line, 3);
+        assert_eq!(options.parse_borrowed(test_data).unwrap().to_owned(), map);
+        assert_eq!(
+            map.globs()[1].key_path,
+            ["net", "ipv4", "neigh", "*", "gc_stale_time"]
+        );
+
+        // A glob in the header makes every key below it a glob.
+        let options = ParseOptions::strict().sections(true);
+        for test_data in [
+            "[net.*]\nfoo = 1\nfoo = 2\n",
+            "[a.*]\nb = 1\n[a.*.b]\nc = 1\n",
+        ] {
+            let map = options.parse_str(test_data).unwrap();
+            assert!(map.is_empty());
+            assert_eq!(
+                options.parse_borrowed(test_data).unwrap().to_owned(),
+                map,
+                "{:?}",
+                test_data
+            );
+        }
     }
 
     #[test]
-    fn ng_with_zero_length_value() {
-        let test_data =
-"foo =
+    fn ng_sections() {
+        let options = ParseOptions::new().sections(true);
+        for (test_data, kind, line, column) in [
+            ("[log\nfile = a\n", ParseErrorKind::InvalidSection, 1, 5),
+            (
+                "foo = 1\n  [log] file = a\n",
+                ParseErrorKind::InvalidSection,
+                2,
+                8,
+            ),
+            ("[net ipv4]\n", ParseErrorKind::WhitespaceInKey, 1, 5),
+        ] {
+            let err = options.parse_str(test_data).unwrap_err();
+            let err = err.downcast_ref::<ParseError>().unwrap();
+            assert_eq!(
+                (&err.kind, err.line, err.column),
+                (&kind, line, column),
+                "{:?}",
+                test_data
+            );
+        }
+
+        let report = options
+            .parse_reader_recovering("[log\nfile = a\n[net]\nip_forward = 1\n".as_bytes())
+            .unwrap();
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.config.keys().collect::<Vec<_>>(), vec!["net"]);
+
+        let err = parse_str("[log]\n").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>().unwrap().kind,
+            ParseErrorKind::MissingDelimiter
+        );
+    }
+
+    #[test]
+    fn ok_operators() {
+        let options = ParseOptions::new().operators(true).inline_comments(true);
+        let test_data = "net.core.somaxconn = 1024
+kernel.modules = a
+kernel.modules += b c # more
+!net.core.somaxconn
 ";
+        let map = options.parse_str(test_data).unwrap();
+        assert_eq!(
+            map.leaves(),
+            parse_str("kernel.modules = a b c\n").unwrap().leaves()
+        );
+        assert_eq!(map.entry_origin("kernel.modules").unwrap().line, 3);
+        assert_eq!(map.entry_comment("kernel.modules"), Some("more"));
+        assert_eq!(map.operations().len(), 1);
+        assert_eq!(map.operations()[0].key_path, ["net", "core", "somaxconn"]);
+        assert_eq!(map.operations()[0].kind, OperationKind::Unset);
 
-        let mut f = NamedTempFile::new().unwrap();
-        f.write_all(test_data.as_bytes()).unwrap();
+        let map = parse_str("kernel.modules+=b\n").unwrap();
+        assert_eq!(
+            map.get_path(&["kernel", "modules+"]),
+            Some(&SysctlConfigValue::String("b".to_string()))
+        );
+        assert!(map.operations().is_empty());
+    }
 
-        let map = load_sysctl(f.path().to_str().unwrap().to_string());
-        assert!(map.is_err());
+    #[test]
+    fn ok_unset_assigned_key() {
+        let map = ParseOptions::new()
+            .operators(true)
+            .parse_str("a = 1\n!a\n")
+            .unwrap();
+        assert!(!map.contains_key("a"));
+        assert_eq!(map.operations().len(), 1);
+        assert_eq!(map.operations()[0].key_path, ["a"]);
+        assert_eq!(map.operations()[0].kind, OperationKind::Unset);
+        assert_eq!(map.operations()[0].origin.line, 2);
     }
 
     #[test]
-    fn ng_with_whitespace_key() {
-        let test_data =
-"foo bar = baz
+    fn ok_merge_operations() {
+        let options = ParseOptions::new().operators(true);
+        let mut map =
+            parse_str("kernel.modules = a\nnet.core.somaxconn = 1024\nvm.swappiness = 60\n")
+                .unwrap();
+        let test_data = "kernel.modules += b
+!net.core.somaxconn
+vm.swappiness = 10
+!vm.swappiness
+fs.file-max += 100
 ";
+        let drop_in = options.parse_str(test_data).unwrap();
+        assert_eq!(
+            drop_in
+                .operations()
+                .iter()
+                .map(|op| op.origin.line)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 4, 5]
+        );
+        map.merge(drop_in);
 
-        let mut f = NamedTempFile::new().unwrap();
-        f.write_all(test_data.as_bytes()).unwrap();
+        assert_eq!(
+            map,
+            parse_str("kernel.modules = a b\nfs.file-max = 100\n").unwrap()
+        );
+        assert_eq!(map.keys().collect::<Vec<_>>(), vec!["kernel", "fs"]);
+        assert_eq!(map.entry_origin("kernel.modules").unwrap().line, 1);
+        assert!(map.operations().is_empty());
+    }
 
-        let map = load_sysctl(f.path().to_str().unwrap().to_string());
-        assert!(map.is_err());
+    #[test]
+    fn ng_operators() {
+        let options = ParseOptions::new().operators(true);
+        for (test_data, kind, column) in [
+            ("!\n", ParseErrorKind::EmptyKey, 2),
+            (
+                "! net.core somaxconn\n",
+                ParseErrorKind::WhitespaceInKey,
+                11,
+            ),
+            ("+= 1\n", ParseErrorKind::EmptyKey, 2),
+            (
+                "kernel = 1\nkernel.modules += a\n",
+                ParseErrorKind::LeafBranchConflict {
+                    other_key: "kernel".to_string(),
+                    other: Box::new(Origin {
+                        path: None,
+                        line: 1,
+                    }),
+                },
+                1,
+            ),
+        ] {
+            let err = options.parse_str(test_data).unwrap_err();
+            let err = err.downcast_ref::<ParseError>().unwrap();
+            assert_eq!((&err.kind, err.column), (&kind, column), "{:?}", test_data);
+        }
+
+        let err = parse_str("!net.core.somaxconn\n").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>().unwrap().kind,
+            ParseErrorKind::MissingDelimiter
+        );
+
+        let config = options.parse_str("kernel.modules += a\n").unwrap();
+        assert!(Dialect::Systemd.serialize(&config).is_err());
+    }
+
+    #[test]
+    fn ok_limits() {
+        let options = ParseOptions::new()
+            .max_file_size(13)
+            .max_line_length(5)
+            .max_key_depth(1)
+            .max_keys(1)
+            .max_value_length(1);
+        let map = options.parse_str("a = 1\r\na = 2\n").unwrap();
+        assert_eq!(
+            map.get("a"),
+            Some(&SysctlConfigValue::String("2".to_string()))
+        );
+        assert_eq!(
+            options
+                .parse_borrowed("a = 1\r\na = 2\n")
+                .unwrap()
+                .to_owned(),
+            map
+        );
+
+        // A `-key` line past a limit is skipped like any other invalid `-key` line.
+        let options = ParseOptions::new().max_keys(1);
+        assert_eq!(
+            options.parse_str("a = 1\n-b = 2\n-c\n").unwrap(),
+            parse_str("a = 1\n").unwrap()
+        );
+        assert_eq!(
+            options
+                .parse_borrowed("a = 1\n-b = 2\n-c\n")
+                .unwrap()
+                .to_owned(),
+            parse_str("a = 1\n").unwrap()
+        );
+
+        let long = "x".repeat(10_000);
+        let report = ParseOptions::new()
+            .max_line_length(16)
+            .parse_reader_recovering(format!("a = 1\nb = {}\nc = 3\n", long).as_bytes())
+            .unwrap();
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(
+            report.errors[0].kind,
+            ParseErrorKind::LineTooLong { limit: 16 }
+        );
+        assert_eq!(report.config.keys().collect::<Vec<_>>(), vec!["a", "c"]);
+        assert_eq!(report.config.entry_origin("c").unwrap().line, 3);
+
+        // Lines rejected for another reason don't use up `max_keys`.
+        let options = ParseOptions::new().max_keys(2);
+        let report = options
+            .parse_reader_recovering("a = 1\na.b = 2\nc = 3\n".as_bytes())
+            .unwrap();
+        assert_eq!(report.errors.len(), 1);
+        assert!(matches!(
+            report.errors[0].kind,
+            ParseErrorKind::LeafBranchConflict { .. }
+        ));
+        assert_eq!(report.config.keys().collect::<Vec<_>>(), vec!["a", "c"]);
+        let test_data = "a = 1\n-a.b = 2\nc = 3\n";
+        assert_eq!(
+            options.parse_borrowed(test_data).unwrap().to_owned(),
+            options.parse_str(test_data).unwrap()
+        );
+        let options = options.conflict_policy(ConflictPolicy::FirstWins);
+        assert!(options.parse_str("a = 1\na.b = 2\nc = 3\n").is_ok());
+        assert!(options.parse_borrowed("a = 1\na.b = 2\nc = 3\n").is_ok());
+
+        // The BOM is not part of the first line.
+        let options = ParseOptions::new().max_line_length(10);
+        assert_eq!(
+            options.parse_str("\u{FEFF}abc=123456\n").unwrap(),
+            parse_str("abc=123456\n").unwrap()
+        );
+        assert_eq!(
+            options
+                .parse_borrowed("\u{FEFF}abc=123456\n")
+                .unwrap()
+                .to_owned(),
+            parse_str("abc=123456\n").unwrap()
+        );
+        let err = options.parse_str("\u{FEFF}abc=1234567\n").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>().unwrap().kind,
+            ParseErrorKind::LineTooLong { limit: 10 }
+        );
+
+        let report = ParseOptions::new()
+            .max_file_size(8)
+            .parse_reader_recovering("a = 1\nb = 2\nc = 3\n".as_bytes())
+            .unwrap();
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.config.keys().collect::<Vec<_>>(), vec!["a"]);
+    }
+
+    #[test]
+    fn ng_limits() {
+        let deep = format!("{} = 1\n", vec!["a"; 100_000].join("."));
+        for (options, test_data, kind, line, column) in [
+            (
+                ParseOptions::new().max_file_size(20),
+                "kernel.sysrq = 1\nvm.swappiness = 10\n",
+                ParseErrorKind::FileTooLarge { limit: 20 },
+                2,
+                4,
+            ),
+            (
+                ParseOptions::new().max_line_length(10),
+                "a = 1\nkernel.sysrq = 16\nb = 2\n",
+                ParseErrorKind::LineTooLong { limit: 10 },
+                2,
+                11,
+            ),
+            (
+                ParseOptions::new().max_key_depth(3),
+                "a.b.c = 1\na.b.c.d = 2\n",
+                ParseErrorKind::KeyTooDeep { limit: 3 },
+                2,
+                1,
+            ),
+            (
+                ParseOptions::new().max_key_depth(3).sections(true),
+                "[a.b]\nc = 1\n  c.d = 2\n",
+                ParseErrorKind::KeyTooDeep { limit: 3 },
+                3,
+                3,
+            ),
+            (
+                ParseOptions::new(),
+                &deep,
+                ParseErrorKind::KeyTooDeep { limit: 128 },
+                1,
+                1,
+            ),
+            (
+                ParseOptions::new().max_keys(2),
+                "a = 1\na = 2\nb.* = 1\nc = 1\n",
+                ParseErrorKind::TooManyKeys { limit: 2 },
+                4,
+                1,
+            ),
+            (
+                ParseOptions::new().max_value_length(4),
+                "a = 1234\nb = 12345\n",
+                ParseErrorKind::ValueTooLong { limit: 4 },
+                2,
+                1,
+            ),
+            (
+                ParseOptions::new()
+                    .max_value_length(4)
+                    .line_continuation(true),
+                "a = 12\\\n345\n",
+                ParseErrorKind::ValueTooLong { limit: 4 },
+                1,
+                1,
+            ),
+        ] {
+            let err = options.parse_str(test_data).unwrap_err();
+            let err = err.downcast_ref::<ParseError>().unwrap();
+            assert_eq!(
+                (&err.kind, err.line, err.column),
+                (&kind, line, column),
+                "{:?}",
+                test_data
+            );
+
+            let expected = err.clone();
+            let err = options.parse_borrowed(test_data).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<ParseError>().unwrap(),
+                &expected,
+                "parse_borrowed: {:?}",
+                test_data
+            );
+        }
+
+        let err = ParseOptions::new()
+            .max_line_length(10)
+            .parse_str("kernel.sysrq = 16\n")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "1:11: line longer than 10 bytes: \"kernel.sys\""
+        );
+        let err = ParseOptions::new()
+            .max_file_size(20)
+            .parse_borrowed("kernel.sysrq = 1\nvm.swappiness = 10\n")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "2:4: file larger than 20 bytes: \"vm.\"");
     }
 }
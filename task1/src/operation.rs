@@ -0,0 +1,63 @@
+use std::borrow::Cow;
+
+use crate::{table::Table, ConflictPolicy, Origin, SysctlConfig, SysctlConfigValue};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OperationKind {
+    /// `key += value`: appends `value` as another field of the value at `key`, or assigns it
+    /// if `key` has no value.
+    Append(String),
+    /// `!key`: removes `key`, and any tables left empty by that.
+    Unset,
+}
+
+/// A `+=` or `!` line to apply to a base config. `SysctlConfig::merge` applies it to the config
+/// being merged into. A `+=` is only kept if the file it is in does not assign its key; a `!` is
+/// always kept, since the key may also be assigned by the base.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Operation {
+    pub key_path: Vec<String>,
+    pub kind: OperationKind,
+    pub origin: Origin,
+}
+
+impl SysctlConfig {
+    /// The `+=` and `!` lines left to apply to a base config, in file order. Only produced with
+    /// `ParseOptions::operators`.
+    pub fn operations(&self) -> &[Operation] {
+        &self.extras.operations
+    }
+
+    // Applies `op` to this config. An append to a table replaces the table.
+    pub(crate) fn apply(&mut self, op: Operation) {
+        let keys = op.key_path;
+        match op.kind {
+            OperationKind::Unset => {
+                self.remove_path(&keys);
+            }
+            OperationKind::Append(value) => {
+                let comment = self.comment_at(&keys).map(|c| Cow::Owned(c.to_string()));
+                let value = match self.get_path(&keys) {
+                    Some(SysctlConfigValue::String(old)) => append_field(old, &value),
+                    _ => value,
+                };
+                let _ = self.insert_path(
+                    &keys,
+                    Cow::Owned(value),
+                    op.origin,
+                    comment,
+                    ConflictPolicy::LastWins,
+                );
+            }
+        }
+    }
+}
+
+// Adds `field` to the whitespace-separated fields of `value`.
+pub(crate) fn append_field(value: &str, field: &str) -> String {
+    if value.is_empty() {
+        field.to_string()
+    } else {
+        format!("{} {}", value, field)
+    }
+}
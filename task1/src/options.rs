@@ -0,0 +1,418 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use anyhow::Result;
+
+use crate::{
+    load_sysctl_from_reader, Entries, ParseErrorKind, ParseReport, SysctlConfig, VariableSource,
+};
+
+/// What to do when a key is assigned both as a value and as a table, e.g. `foo = 1` and
+/// `foo.bar = 2`. Applies the same way whichever of the two lines comes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Reject the later line with `ParseErrorKind::LeafBranchConflict`.
+    #[default]
+    Error,
+    /// The later line replaces the earlier value or table.
+    LastWins,
+    /// The later line is skipped.
+    FirstWins,
+}
+
+/// Parser settings. The free functions (`parse_str`, `load_path`, ...) use
+/// `ParseOptions::default()`.
+///
+/// `strict` and the dialect presets `procps`, `systemd`, `busybox` and `freebsd` can be adjusted
+/// further with the setters, e.g. `ParseOptions::systemd().inline_comments(true)`.
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    pub(crate) conflict_policy: ConflictPolicy,
+    pub(crate) empty_values: bool,
+    pub(crate) whitespace_in_keys: bool,
+    pub(crate) indented_comments: bool,
+    pub(crate) ignore_error_prefix: bool,
+    pub(crate) semicolon_comments: bool,
+    pub(crate) globs: bool,
+    pub(crate) slash_separator: bool,
+    pub(crate) deny_duplicates: bool,
+    pub(crate) quoted_values: bool,
+    pub(crate) line_continuation: bool,
+    pub(crate) inline_comments: bool,
+    pub(crate) includes: bool,
+    pub(crate) max_include_depth: usize,
+    pub(crate) variables: Option<VariableSource>,
+    pub(crate) key_references: bool,
+    pub(crate) lossy_utf8: bool,
+    pub(crate) sections: bool,
+    pub(crate) operators: bool,
+    pub(crate) max_file_size: usize,
+    pub(crate) max_line_length: usize,
+    pub(crate) max_key_depth: usize,
+    pub(crate) max_keys: usize,
+    pub(crate) max_value_length: usize,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            conflict_policy: ConflictPolicy::default(),
+            empty_values: false,
+            whitespace_in_keys: false,
+            indented_comments: false,
+            ignore_error_prefix: true,
+            semicolon_comments: true,
+            globs: true,
+            slash_separator: true,
+            deny_duplicates: false,
+            quoted_values: false,
+            line_continuation: false,
+            inline_comments: false,
+            includes: false,
+            max_include_depth: 16,
+            variables: None,
+            key_references: false,
+            lossy_utf8: false,
+            sections: false,
+            operators: false,
+            max_file_size: usize::MAX,
+            max_line_length: usize::MAX,
+            max_key_depth: 128,
+            max_keys: usize::MAX,
+            max_value_length: usize::MAX,
+        }
+    }
+}
+
+impl ParseOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The default rules, and a key assigned twice is an error rather than an override.
+    pub fn strict() -> Self {
+        Self::default().deny_duplicates(true)
+    }
+
+    /// The rules of procps `sysctl -p`: comments and blank lines may be indented, and a key may
+    /// be assigned both as a value and as a table, the later line winning.
+    pub fn procps() -> Self {
+        Self::default()
+            .indented_comments(true)
+            .conflict_policy(ConflictPolicy::LastWins)
+    }
+
+    /// The rules of systemd-sysctl: like `procps`, but values may also be empty.
+    pub fn systemd() -> Self {
+        Self::procps().empty_values(true)
+    }
+
+    /// The rules of busybox `sysctl -p`: comments may be indented and keys may contain
+    /// whitespace, but there are no `-` prefixes or globs.
+    pub fn busybox() -> Self {
+        Self::default()
+            .indented_comments(true)
+            .whitespace_in_keys(true)
+            .ignore_error_prefix(false)
+            .globs(false)
+            .conflict_policy(ConflictPolicy::LastWins)
+    }
+
+    /// The rules of FreeBSD `/etc/rc.d/sysctl`: only `#` starts a comment, also after a value,
+    /// and keys are separated by dots only. There are no `-` prefixes or globs.
+    pub fn freebsd() -> Self {
+        Self::default()
+            .indented_comments(true)
+            .semicolon_comments(false)
+            .inline_comments(true)
+            .ignore_error_prefix(false)
+            .globs(false)
+            .slash_separator(false)
+            .conflict_policy(ConflictPolicy::LastWins)
+    }
+
+    pub fn conflict_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.conflict_policy = policy;
+        self
+    }
+
+    /// Accepts `key =` with nothing after the `=`, assigning an empty string, instead of
+    /// rejecting it with `ParseErrorKind::EmptyValue`.
+    pub fn empty_values(mut self, allow: bool) -> Self {
+        self.empty_values = allow;
+        self
+    }
+
+    /// Accepts whitespace inside keys, e.g. `foo bar = 1`, instead of rejecting it with
+    /// `ParseErrorKind::WhitespaceInKey`.
+    pub fn whitespace_in_keys(mut self, allow: bool) -> Self {
+        self.whitespace_in_keys = allow;
+        self
+    }
+
+    /// Treats lines that start with `#` or `;` after leading whitespace as comments, and lines
+    /// of only whitespace as blank. By default both must start in the first column.
+    pub fn indented_comments(mut self, allow: bool) -> Self {
+        self.indented_comments = allow;
+        self
+    }
+
+    /// Whether `;` starts a comment like `#`. On by default.
+    pub fn semicolon_comments(mut self, enable: bool) -> Self {
+        self.semicolon_comments = enable;
+        self
+    }
+
+    /// Whether a key containing `*`, `?` or `[` is a glob, see `SysctlConfig::globs`, and a
+    /// `-key` line without a value excludes `key` from them. On by default; when off, such a key
+    /// is assigned as written and a `-key` line is an ignored error.
+    pub fn globs(mut self, enable: bool) -> Self {
+        self.globs = enable;
+        self
+    }
+
+    /// Whether `/` separates key components as described in `split_key`. On by default; when
+    /// off, keys are split on dots only and `/` is part of a component.
+    pub fn slash_separator(mut self, enable: bool) -> Self {
+        self.slash_separator = enable;
+        self
+    }
+
+    /// Whether a leading `-` makes errors on its line ignored and, with `globs`, marks `-key`
+    /// exclusions. On by default; when off, the `-` is part of the key.
+    pub fn ignore_error_prefix(mut self, enable: bool) -> Self {
+        self.ignore_error_prefix = enable;
+        self
+    }
+
+    /// Rejects a key that is assigned twice with `ParseErrorKind::DuplicateKey` instead of
+    /// letting the later value win.
+    pub fn deny_duplicates(mut self, deny: bool) -> Self {
+        self.deny_duplicates = deny;
+        self
+    }
+
+    /// Accepts values in double or single quotes, which may contain leading or trailing
+    /// whitespace, be empty, and use the escapes `\n`, `\t`, `\\`, `\"` and `\'`.
+    /// Off by default, so that quotes are part of the value as in procps.
+    pub fn quoted_values(mut self, enable: bool) -> Self {
+        self.quoted_values = enable;
+        self
+    }
+
+    /// Joins a line ending in `\` with the next one, dropping the backslash. Errors still
+    /// report the physical line they occur on. Comment lines are never continued.
+    pub fn line_continuation(mut self, enable: bool) -> Self {
+        self.line_continuation = enable;
+        self
+    }
+
+    /// Strips a trailing comment from values, e.g. `vm.swappiness = 10  # tuned for DB hosts`
+    /// assigns `10`. The comment must start with `#` or `;` after whitespace and is kept as
+    /// `Entry::comment` and `SysctlConfig::entry_comment`. A `#` or `;` inside a quoted value
+    /// does not start a comment.
+    pub fn inline_comments(mut self, enable: bool) -> Self {
+        self.inline_comments = enable;
+        self
+    }
+
+    /// Reads `include other.conf` and `include conf.d/*.conf` lines, which apply the named
+    /// files at that point as if their lines were written there. Relative paths are resolved
+    /// against the directory of the including file, or the current directory when reading
+    /// from a string or reader. Only the file name may contain a glob; matches are read in
+    /// sorted order, and a glob matching nothing includes nothing.
+    pub fn includes(mut self, enable: bool) -> Self {
+        self.includes = enable;
+        self
+    }
+
+    /// How many `include` lines may be nested, 16 by default. A deeper include is rejected
+    /// with `ParseErrorKind::IncludeDepth`.
+    pub fn max_include_depth(mut self, depth: usize) -> Self {
+        self.max_include_depth = depth;
+        self
+    }
+
+    /// Expands `${VAR}` and `${VAR:-default}` in values from `source`, e.g.
+    /// `ParseOptions::new().variables(VariableSource::Env)`. The default applies when VAR is
+    /// unset or empty. Unset variables without a default fail the parse with
+    /// `UnresolvedVariables`, listing each of them with its line. `Entries` yields values as
+    /// written; expansion happens when the config is built.
+    pub fn variables(mut self, source: impl Into<VariableSource>) -> Self {
+        self.variables = Some(source.into());
+        self
+    }
+
+    /// Resolves `${other.key}` references between values once the whole config is built, see
+    /// `SysctlConfig::resolve_references`. A failure is returned as `ReferenceError`. The
+    /// recovering functions and `parse_borrowed` leave references as written.
+    pub fn key_references(mut self, enable: bool) -> Self {
+        self.key_references = enable;
+        self
+    }
+
+    /// Decodes lines that are not valid UTF-8 with U+FFFD replacement characters instead of
+    /// rejecting them with `ParseErrorKind::InvalidUtf8`.
+    pub fn lossy_utf8(mut self, enable: bool) -> Self {
+        self.lossy_utf8 = enable;
+        self
+    }
+
+    /// Reads INI-style `[net.ipv4]` headers, which prefix the keys of the following lines until
+    /// the next header, so that `ip_forward = 1` below it assigns `net.ipv4.ip_forward`. `[]`
+    /// ends the prefix. A malformed header is an error, and the lines below it are skipped
+    /// until the next valid header.
+    pub fn sections(mut self, enable: bool) -> Self {
+        self.sections = enable;
+        self
+    }
+
+    /// Reads `key += value`, which appends `value` as another whitespace-separated field of
+    /// `key`, and `!key`, which removes `key`. Both take effect in the file itself. A `+=` whose
+    /// key the file doesn't assign, and every `!`, is also kept in `SysctlConfig::operations`
+    /// and applied by `SysctlConfig::merge`, so that a drop-in can extend or remove a value of
+    /// the file it is merged onto.
+    /// `parse_borrowed` rejects both with `ParseErrorKind::UnsupportedOperator`.
+    pub fn operators(mut self, enable: bool) -> Self {
+        self.operators = enable;
+        self
+    }
+
+    /// Stops reading a file at `limit` bytes with `ParseErrorKind::FileTooLarge`. Each included
+    /// file has its own limit. Unlimited by default.
+    pub fn max_file_size(mut self, limit: usize) -> Self {
+        self.max_file_size = limit;
+        self
+    }
+
+    /// Rejects lines longer than `limit` bytes, not counting the line ending, with
+    /// `ParseErrorKind::LineTooLong`. The rest of such a line is skipped without being held in
+    /// memory. Unlimited by default.
+    pub fn max_line_length(mut self, limit: usize) -> Self {
+        self.max_line_length = limit;
+        self
+    }
+
+    /// Rejects keys with more than `limit` components, including those of a `[section]`
+    /// header, with `ParseErrorKind::KeyTooDeep`. Defaults to 128.
+    pub fn max_key_depth(mut self, limit: usize) -> Self {
+        self.max_key_depth = limit;
+        self
+    }
+
+    /// Rejects the entry that would add a key beyond the first `limit` with
+    /// `ParseErrorKind::TooManyKeys`. Values, globs, exclusions and operations each count as
+    /// a key, across included files; assigning a key again does not. Unlimited by default.
+    pub fn max_keys(mut self, limit: usize) -> Self {
+        self.max_keys = limit;
+        self
+    }
+
+    /// Rejects values longer than `limit` bytes after quotes, continuations and variables are
+    /// resolved, with `ParseErrorKind::ValueTooLong`. Unlimited by default.
+    pub fn max_value_length(mut self, limit: usize) -> Self {
+        self.max_value_length = limit;
+        self
+    }
+
+    /// Iterates over the entries of `reader` without building a `SysctlConfig`.
+    pub fn entries<R: BufRead>(&self, reader: R) -> Entries<R> {
+        Entries::with_options(reader, None, self)
+    }
+
+    pub fn load_path(&self, path: impl AsRef<Path>) -> Result<SysctlConfig> {
+        self.finish(self.load(path.as_ref(), false)?)
+    }
+
+    pub fn parse_reader(&self, reader: impl BufRead) -> Result<SysctlConfig> {
+        self.finish(load_sysctl_from_reader(reader, None, self, false)?)
+    }
+
+    pub fn parse_str(&self, s: &str) -> Result<SysctlConfig> {
+        self.parse_reader(s.as_bytes())
+    }
+
+    /// Like `load_path`, but keeps going after an invalid line and reports all of them.
+    /// Only I/O failures are returned as `Err`.
+    pub fn load_path_recovering(&self, path: impl AsRef<Path>) -> Result<ParseReport> {
+        self.load(path.as_ref(), true)
+    }
+
+    /// Like `parse_reader`, but keeps going after an invalid line and reports all of them.
+    /// Only I/O failures are returned as `Err`.
+    pub fn parse_reader_recovering(&self, reader: impl BufRead) -> Result<ParseReport> {
+        load_sysctl_from_reader(reader, None, self, true)
+    }
+
+    fn finish(&self, report: ParseReport) -> Result<SysctlConfig> {
+        let mut config = report.into_result()?;
+        if self.key_references {
+            config.resolve_references()?;
+        }
+        Ok(config)
+    }
+
+    // The size limit exceeded by the physical line `line`, which starts at byte `start` of the
+    // input and ends with its line ending at `end`, with the byte offset in `line` of the first
+    // byte past the limit.
+    pub(crate) fn exceeded_size(
+        &self,
+        line: &[u8],
+        start: usize,
+        end: usize,
+    ) -> Option<(ParseErrorKind, usize)> {
+        if end > self.max_file_size {
+            Some((
+                ParseErrorKind::FileTooLarge {
+                    limit: self.max_file_size,
+                },
+                self.max_file_size.saturating_sub(start).min(line.len()),
+            ))
+        } else if line.len() > self.max_line_length {
+            Some((
+                ParseErrorKind::LineTooLong {
+                    limit: self.max_line_length,
+                },
+                self.max_line_length,
+            ))
+        } else {
+            None
+        }
+    }
+
+    // The limit exceeded by storing an entry with a `depth`-component key and a value of
+    // `value_len` bytes, with `keys` keys stored so far. The caller counts the key once it is
+    // stored, if the entry `adds_key`.
+    pub(crate) fn exceeded_limit(
+        &self,
+        depth: usize,
+        value_len: usize,
+        adds_key: bool,
+        keys: usize,
+    ) -> Option<ParseErrorKind> {
+        if depth > self.max_key_depth {
+            return Some(ParseErrorKind::KeyTooDeep {
+                limit: self.max_key_depth,
+            });
+        }
+        if value_len > self.max_value_length {
+            return Some(ParseErrorKind::ValueTooLong {
+                limit: self.max_value_length,
+            });
+        }
+        if adds_key && keys >= self.max_keys {
+            return Some(ParseErrorKind::TooManyKeys {
+                limit: self.max_keys,
+            });
+        }
+        None
+    }
+
+    fn load(&self, path: &Path, recover: bool) -> Result<ParseReport> {
+        let file = File::open(path)?;
+        load_sysctl_from_reader(BufReader::new(file), Some(path), self, recover)
+    }
+}
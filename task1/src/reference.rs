@@ -0,0 +1,323 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{join_key, split_key, Origin, SysctlConfig, SysctlConfigValue};
+
+/// Why `SysctlConfig::resolve_references` failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReferenceError {
+    /// The keys reference each other in a loop. The first key is repeated at the end, e.g.
+    /// `["a.b", "c.d", "a.b"]`.
+    Cycle { keys: Vec<String> },
+    /// `key`, assigned at `origin`, references `reference`, which is not assigned a value.
+    UnknownKey {
+        key: String,
+        reference: String,
+        origin: Option<Origin>,
+    },
+}
+
+impl fmt::Display for ReferenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReferenceError::Cycle { keys } => write!(f, "reference cycle: {}", keys.join(" -> ")),
+            ReferenceError::UnknownKey {
+                key,
+                reference,
+                origin,
+            } => {
+                if let Some(origin) = origin {
+                    write!(f, "{}: ", origin)?;
+                }
+                write!(f, "{} references unknown key {}", key, reference)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReferenceError {}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Unresolved,
+    Resolving,
+    Resolved,
+}
+
+// The leaves of a config by joined key, with their values being resolved in place.
+struct Resolver {
+    keys: Vec<String>,
+    values: Vec<String>,
+    states: Vec<State>,
+    index: HashMap<String, usize>,
+}
+
+// A key being resolved: its value up to `rest` with references replaced.
+struct Frame {
+    key: usize,
+    resolved: String,
+    rest: usize,
+}
+
+impl Resolver {
+    // Resolves key `root` and, first, the keys it references. Uses an explicit stack so that
+    // long reference chains can't overflow the call stack.
+    fn resolve(&mut self, root: usize, config: &SysctlConfig) -> Result<(), ReferenceError> {
+        if self.states[root] == State::Resolved {
+            return Ok(());
+        }
+        self.states[root] = State::Resolving;
+        let mut stack = vec![Frame {
+            key: root,
+            resolved: String::new(),
+            rest: 0,
+        }];
+        while let Some(frame) = stack.last_mut() {
+            let i = frame.key;
+            let rest = &self.values[i][frame.rest..];
+            let Some((before, reference, default, after)) = next_reference(rest) else {
+                frame.resolved.push_str(rest);
+                let frame = stack.pop().unwrap();
+                self.values[i] = frame.resolved;
+                self.states[i] = State::Resolved;
+                continue;
+            };
+
+            let next = frame.rest + rest.len() - after.len();
+            match (
+                self.index.get(&join_key(&split_key(reference))).copied(),
+                default,
+            ) {
+                (Some(j), _) => match self.states[j] {
+                    State::Resolved => {
+                        frame.resolved.push_str(before);
+                        frame.resolved.push_str(&self.values[j]);
+                        frame.rest = next;
+                    }
+                    State::Resolving => {
+                        let start = stack.iter().position(|f| f.key == j).unwrap_or(0);
+                        let mut keys = stack[start..]
+                            .iter()
+                            .map(|f| self.keys[f.key].clone())
+                            .collect::<Vec<String>>();
+                        keys.push(self.keys[j].clone());
+                        return Err(ReferenceError::Cycle { keys });
+                    }
+                    // Comes back to this reference once `j` is resolved.
+                    State::Unresolved => {
+                        self.states[j] = State::Resolving;
+                        stack.push(Frame {
+                            key: j,
+                            resolved: String::new(),
+                            rest: 0,
+                        });
+                    }
+                },
+                (None, Some(default)) => {
+                    frame.resolved.push_str(before);
+                    frame.resolved.push_str(default);
+                    frame.rest = next;
+                }
+                (None, None) => {
+                    return Err(ReferenceError::UnknownKey {
+                        key: self.keys[i].clone(),
+                        reference: reference.to_string(),
+                        origin: config.entry_origin(&self.keys[i]).cloned(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces the references in a value that can't be referenced itself, such as a glob's.
+    fn substitute(&self, value: &str) -> Option<String> {
+        let mut resolved = String::new();
+        let mut rest = value;
+        while let Some((before, reference, default, after)) = next_reference(rest) {
+            resolved.push_str(before);
+            match self.index.get(&join_key(&split_key(reference))) {
+                Some(j) => resolved.push_str(&self.values[*j]),
+                None => resolved.push_str(default?),
+            }
+            rest = after;
+        }
+        resolved.push_str(rest);
+        Some(resolved)
+    }
+}
+
+// Finds the first `${key}` or `${key:-default}` in `value` that names a key, i.e. contains a `.`
+// or `/`, and returns the text before it, the key, the default and the text after it. Other
+// `${...}` are kept as text.
+fn next_reference(value: &str) -> Option<(&str, &str, Option<&str>, &str)> {
+    let mut from = 0;
+    loop {
+        let start = from + value[from..].find("${")?;
+        let len = value[start..].find('}')? + 1;
+        let body = &value[start + 2..start + len - 1];
+        let (reference, default) = match body.split_once(":-") {
+            Some((reference, default)) => (reference, Some(default)),
+            None => (body, None),
+        };
+        if reference.contains(['.', '/']) && !reference.contains(char::is_whitespace) {
+            return Some((&value[..start], reference, default, &value[start + len..]));
+        }
+        from = start + 2;
+    }
+}
+
+impl SysctlConfig {
+    /// Replaces `${other.key}` in values with the resolved value of `other.key`, or with
+    /// `default` for `${other.key:-default}` if that key has no value. Only references
+    /// containing a `.` or `/` name keys; others, like environment variables, are left alone.
+    /// Referenced keys are resolved first, whatever their order in the file. Glob values may
+    /// reference keys but can't be referenced themselves.
+    pub fn resolve_references(&mut self) -> Result<(), ReferenceError> {
+        let leaves = self.leaves();
+        let keys = leaves
+            .iter()
+            .map(|(path, _)| join_key(path))
+            .collect::<Vec<String>>();
+        let mut resolver = Resolver {
+            index: keys
+                .iter()
+                .enumerate()
+                .map(|(i, key)| (key.clone(), i))
+                .collect(),
+            states: vec![State::Unresolved; keys.len()],
+            values: leaves.iter().map(|(_, value)| value.clone()).collect(),
+            keys,
+        };
+
+        for i in 0..leaves.len() {
+            resolver.resolve(i, self)?;
+        }
+        let mut globs = vec![];
+        for glob in self.extras.globs.iter() {
+            match resolver.substitute(&glob.value) {
+                Some(value) => globs.push(value),
+                None => {
+                    let reference =
+                        next_reference(&glob.value).map_or("", |(_, reference, _, _)| reference);
+                    return Err(ReferenceError::UnknownKey {
+                        key: join_key(&glob.key_path),
+                        reference: reference.to_string(),
+                        origin: Some(glob.origin.clone()),
+                    });
+                }
+            }
+        }
+
+        for ((path, _), value) in leaves.into_iter().zip(resolver.values) {
+            if let Some(SysctlConfigValue::String(v)) = self.get_path_mut(&path) {
+                *v = value;
+            }
+        }
+        for (glob, value) in self.extras.globs.iter_mut().zip(globs) {
+            glob.value = value;
+        }
+        Ok(())
+    }
+
+    fn get_path_mut<S: AsRef<str>>(&mut self, keys: &[S]) -> Option<&mut SysctlConfigValue> {
+        let (last, parents) = keys.split_last()?;
+        let mut m = self;
+        for key in parents {
+            match m.entries.get_mut(key.as_ref()) {
+                Some(SysctlConfigValue::SysctlConfig(next_m)) => m = next_m,
+                _ => return None,
+            }
+        }
+        m.entries.get_mut(last.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{table, value};
+    use crate::{parse_str, ParseOptions};
+
+    #[test]
+    fn ok_resolve_references() {
+        let test_data = "log.archive = ${log.dir}/archive
+log.dir = ${log.root}/app
+log.root = /var/log
+log.level = ${log.verbosity:-info}
+home = ${HOME}
+";
+
+        let mut map = parse_str(test_data).unwrap();
+        map.resolve_references().unwrap();
+        let log = table(&map, "log");
+        let v = value(log, "archive");
+        assert_eq!(v, "/var/log/app/archive");
+        let v = value(log, "dir");
+        assert_eq!(v, "/var/log/app");
+        let v = value(log, "level");
+        assert_eq!(v, "info");
+        let v = value(&map, "home");
+        assert_eq!(v, "${HOME}");
+    }
+
+    #[test]
+    fn ok_key_references_option() {
+        let options = ParseOptions::new().key_references(true);
+        let map = options
+            .parse_str("net.ipv4.conf.*.rp_filter = ${default.rp_filter}\ndefault.rp_filter = 2\n")
+            .unwrap();
+        assert_eq!(map.globs()[0].value, "2");
+    }
+
+    #[test]
+    fn ng_reference_cycle() {
+        let mut map = parse_str("a.x = 1\na.b = ${c.d}/x\nc.d = ${e.f}\ne.f = ${a/b}\n").unwrap();
+        let err = map.resolve_references().unwrap_err();
+        assert_eq!(
+            err,
+            ReferenceError::Cycle {
+                keys: vec![
+                    "a.b".to_string(),
+                    "c.d".to_string(),
+                    "e.f".to_string(),
+                    "a.b".to_string()
+                ]
+            }
+        );
+        assert_eq!(err.to_string(), "reference cycle: a.b -> c.d -> e.f -> a.b");
+
+        let mut map = parse_str("a.b = ${a.b}\n").unwrap();
+        let err = map.resolve_references().unwrap_err();
+        assert_eq!(
+            err,
+            ReferenceError::Cycle {
+                keys: vec!["a.b".to_string(), "a.b".to_string()]
+            }
+        );
+    }
+
+    #[test]
+    fn ok_long_reference_chain() {
+        let mut test_data = (0..10_000)
+            .map(|i| format!("k.a{} = ${{k.a{}}}/x\n", i, i + 1))
+            .collect::<String>();
+        test_data.push_str("k.a10000 = end\n");
+        let map = ParseOptions::new()
+            .key_references(true)
+            .parse_str(&test_data)
+            .unwrap();
+        let k = table(&map, "k");
+        let v = value(k, "a0");
+        assert_eq!(v, &format!("end{}", "/x".repeat(10_000)));
+    }
+
+    #[test]
+    fn ng_unknown_reference() {
+        let err = ParseOptions::new()
+            .key_references(true)
+            .parse_str("a.x = 1\na.b = ${a.c}\n")
+            .unwrap_err();
+        let err = err.downcast_ref::<ReferenceError>().unwrap();
+        assert_eq!(err.to_string(), "line 2: a.b references unknown key a.c");
+    }
+}
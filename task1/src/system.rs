@@ -0,0 +1,170 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::{load_path, SysctlConfig};
+
+/// Where `load_system` looks for configuration files.
+///
+/// `dirs` are listed from highest to lowest priority: a file in an earlier directory masks a
+/// file with the same name in a later one. `sysctl_conf` is applied after all of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemRoots {
+    pub dirs: Vec<PathBuf>,
+    pub sysctl_conf: Option<PathBuf>,
+}
+
+impl SystemRoots {
+    /// The search path used by systemd-sysctl and `sysctl --system`.
+    pub fn system() -> Self {
+        Self::under("/")
+    }
+
+    /// The system search path relocated below `root`, e.g. a chroot or a test directory.
+    pub fn under(root: impl AsRef<Path>) -> Self {
+        let root = root.as_ref();
+        Self {
+            dirs: [
+                "etc/sysctl.d",
+                "run/sysctl.d",
+                "usr/local/lib/sysctl.d",
+                "usr/lib/sysctl.d",
+            ]
+            .iter()
+            .map(|d| root.join(d))
+            .collect(),
+            sysctl_conf: Some(root.join("etc/sysctl.conf")),
+        }
+    }
+
+    /// Returns the files `load_system` reads, in the order they are applied.
+    pub fn files(&self) -> Result<Vec<PathBuf>> {
+        let mut by_name = BTreeMap::new();
+        for dir in self.dirs.iter() {
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for entry in entries {
+                let path = entry?.path();
+                if path.extension().is_some_and(|e| e == "conf") && !path.is_dir() {
+                    if let Some(name) = path.file_name() {
+                        by_name.entry(name.to_os_string()).or_insert(path);
+                    }
+                }
+            }
+        }
+
+        let mut files = by_name.into_values().collect::<Vec<PathBuf>>();
+        if let Some(sysctl_conf) = &self.sysctl_conf {
+            if sysctl_conf.is_file() {
+                files.push(sysctl_conf.clone());
+            }
+        }
+        Ok(files)
+    }
+}
+
+impl Default for SystemRoots {
+    fn default() -> Self {
+        Self::system()
+    }
+}
+
+/// Loads every file of `roots` and merges them into one config, later files overriding
+/// earlier ones. A file masked by a symlink to `/dev/null` reads as empty.
+pub fn load_system(roots: &SystemRoots) -> Result<SysctlConfig> {
+    let mut config = SysctlConfig::new();
+    for file in roots.files()? {
+        config.merge(load_path(&file)?);
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{table, value};
+
+    fn write(root: &Path, path: &str, data: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn ok_files() {
+        let root = tempfile::tempdir().unwrap();
+        write(root.path(), "usr/lib/sysctl.d/50-default.conf", "");
+        write(root.path(), "usr/lib/sysctl.d/10-base.conf", "");
+        write(root.path(), "usr/lib/sysctl.d/README", "");
+        write(root.path(), "run/sysctl.d/50-default.conf", "");
+        write(root.path(), "etc/sysctl.d/99-local.conf", "");
+        write(root.path(), "etc/sysctl.conf", "");
+
+        let files = SystemRoots::under(root.path()).files().unwrap();
+        assert_eq!(
+            files,
+            vec![
+                root.path().join("usr/lib/sysctl.d/10-base.conf"),
+                root.path().join("run/sysctl.d/50-default.conf"),
+                root.path().join("etc/sysctl.d/99-local.conf"),
+                root.path().join("etc/sysctl.conf"),
+            ]
+        );
+    }
+
+    #[test]
+    fn ok_load_system() {
+        let root = tempfile::tempdir().unwrap();
+        write(
+            root.path(),
+            "usr/lib/sysctl.d/10-base.conf",
+            "kernel.sysrq = 16\nvm.swappiness = 60\n",
+        );
+        write(
+            root.path(),
+            "usr/lib/sysctl.d/50-default.conf",
+            "kernel.panic = 0\n",
+        );
+        write(
+            root.path(),
+            "etc/sysctl.d/50-default.conf",
+            "kernel.panic = 10\n",
+        );
+        write(
+            root.path(),
+            "etc/sysctl.d/90-db.conf",
+            "vm.swappiness = 10\n",
+        );
+        write(root.path(), "etc/sysctl.conf", "kernel.sysrq = 0\n");
+
+        let map = load_system(&SystemRoots::under(root.path())).unwrap();
+
+        let kernel = table(&map, "kernel");
+        let v = value(kernel, "sysrq");
+        assert_eq!(v, "0");
+        let v = value(kernel, "panic");
+        assert_eq!(v, "10");
+
+        let origin = map.entry_origin("vm.swappiness").unwrap();
+        assert_eq!(
+            origin.path.as_deref(),
+            Some(root.path().join("etc/sysctl.d/90-db.conf").as_path())
+        );
+        assert_eq!(origin.line, 1);
+    }
+
+    #[test]
+    fn ok_load_system_without_dirs() {
+        let root = tempfile::tempdir().unwrap();
+        let map = load_system(&SystemRoots::under(root.path())).unwrap();
+        assert!(map.is_empty());
+    }
+}
@@ -0,0 +1,154 @@
+use std::borrow::Cow;
+
+use crate::{join_key, ConflictPolicy, Operation, Origin};
+
+// What a key of a table holds.
+pub(crate) enum Slot<'t, T> {
+    Leaf(&'t str, &'t Origin),
+    Table(&'t T),
+}
+
+// The existing assignment that a new line collided with.
+pub(crate) struct Conflict {
+    pub(crate) key: String,
+    pub(crate) origin: Origin,
+}
+
+// A table that the parser can fill in: `SysctlConfig`, or `SysctlConfigRef`, whose keys and
+// values may borrow from the input. Implementations only provide access to a single level;
+// walking key paths and resolving conflicts is shared by both.
+pub(crate) trait Table<'a>: Default {
+    fn slot(&self, key: &str) -> Option<Slot<'_, Self>>;
+
+    // Every key with what it holds, in insertion order.
+    fn slots(&self) -> Box<dyn Iterator<Item = (&str, Slot<'_, Self>)> + '_>;
+
+    // The table at `key`, created if there is nothing there and replacing a value otherwise.
+    fn table_mut(&mut self, key: Cow<'a, str>) -> &mut Self;
+
+    // The table at `key`, if there is one.
+    fn existing_table_mut(&mut self, key: &str) -> Option<&mut Self>;
+
+    // Sets the value at `key` and returns the value it replaced.
+    fn set_leaf(
+        &mut self,
+        key: Cow<'a, str>,
+        value: Cow<'a, str>,
+        origin: Origin,
+        comment: Option<Cow<'a, str>>,
+    ) -> Option<(Cow<'a, str>, Origin)>;
+
+    // Removes the value or table at `key`. Returns whether there was one.
+    fn remove(&mut self, key: &str) -> bool;
+
+    fn push_glob(&mut self, key_path: Vec<Cow<'a, str>>, value: Cow<'a, str>, origin: Origin);
+
+    fn push_exclusion(&mut self, key_path: Vec<Cow<'a, str>>);
+
+    // Where `+=` and `!` lines are kept for a later merge, or `None` if this table can't keep
+    // them.
+    fn operations_mut(&mut self) -> Option<&mut Vec<Operation>>;
+
+    fn slot_at<S: AsRef<str>>(&self, keys: &[S]) -> Option<Slot<'_, Self>> {
+        let (last, parents) = keys.split_last()?;
+        let mut m = self;
+        for key in parents {
+            match m.slot(key.as_ref()) {
+                Some(Slot::Table(next_m)) => m = next_m,
+                _ => return None,
+            }
+        }
+        m.slot(last.as_ref())
+    }
+
+    // Inserts a leaf, creating intermediate tables, and returns the leaf it replaced. When a
+    // component on the way is already a leaf, or the leaf itself is already a table, `policy`
+    // decides which side survives.
+    fn insert_path<K>(
+        &mut self,
+        keys: &[K],
+        value: Cow<'a, str>,
+        origin: Origin,
+        comment: Option<Cow<'a, str>>,
+        policy: ConflictPolicy,
+    ) -> Result<Option<(Cow<'a, str>, Origin)>, Conflict>
+    where
+        K: AsRef<str> + Clone + Into<Cow<'a, str>>,
+    {
+        let Some((last, parents)) = keys.split_last() else {
+            return Ok(None);
+        };
+        let mut m = self;
+        for (i, key) in parents.iter().enumerate() {
+            if let Some(Slot::Leaf(_, origin)) = m.slot(key.as_ref()) {
+                match policy {
+                    ConflictPolicy::Error => {
+                        return Err(Conflict {
+                            key: join_key(&keys[..=i]),
+                            origin: origin.clone(),
+                        });
+                    }
+                    ConflictPolicy::FirstWins => return Ok(None),
+                    ConflictPolicy::LastWins => {}
+                }
+            }
+            m = m.table_mut(key.clone().into());
+        }
+
+        if let Some(Slot::Table(table)) = m.slot(last.as_ref()) {
+            match (policy, table.first_leaf()) {
+                (ConflictPolicy::Error, Some((mut leaf_keys, leaf_origin))) => {
+                    let mut conflict_keys = keys
+                        .iter()
+                        .map(|k| k.as_ref().to_string())
+                        .collect::<Vec<String>>();
+                    conflict_keys.append(&mut leaf_keys);
+                    return Err(Conflict {
+                        key: join_key(&conflict_keys),
+                        origin: leaf_origin.clone(),
+                    });
+                }
+                (ConflictPolicy::FirstWins, Some(_)) => return Ok(None),
+                _ => {}
+            }
+        }
+        Ok(m.set_leaf(last.clone().into(), value, origin, comment))
+    }
+
+    // Removes the value or table at `keys` and prunes the tables that become empty. Returns
+    // whether anything was removed.
+    fn remove_path<S: AsRef<str>>(&mut self, keys: &[S]) -> bool {
+        let Some((first, rest)) = keys.split_first() else {
+            return false;
+        };
+        let first = first.as_ref();
+        if rest.is_empty() {
+            return self.remove(first);
+        }
+
+        let Some(m) = self.existing_table_mut(first) else {
+            return false;
+        };
+        let removed = m.remove_path(rest);
+        if m.slots().next().is_none() {
+            self.remove(first);
+        }
+        removed
+    }
+
+    // Returns the path and origin of the first leaf in insertion order, if any.
+    fn first_leaf(&self) -> Option<(Vec<String>, &Origin)> {
+        for (key, slot) in self.slots() {
+            match slot {
+                Slot::Leaf(_, origin) => return Some((vec![key.to_string()], origin)),
+                Slot::Table(m) => {
+                    if let Some((mut keys, origin)) = m.first_leaf() {
+                        keys.insert(0, key.to_string());
+                        return Some((keys, origin));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
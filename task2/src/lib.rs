@@ -1,10 +1,17 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashSet,
     io::{BufRead, BufReader, Cursor, Read},
 };
 
 use anyhow::{Error, Result};
 
+use task1::{join_key, split_key};
+
+pub use task1::{
+    ConflictPolicy, Dialect, ParseError, ParseErrorKind, ParseOptions, SysctlConfig,
+    SysctlConfigValue,
+};
+
 struct SysctlConfigSchema {
     key: String,
     typ: SysctlConfigType,
@@ -13,18 +20,14 @@ struct SysctlConfigSchema {
 impl SysctlConfigSchema {
     fn new(key: String, typ: String) -> Result<Self> {
         let typ = SysctlConfigType::from_string(&typ).unwrap();
+        let key = join_key(&split_key(&key));
         Ok(Self { key, typ })
     }
 }
 
 pub struct SysctlConfigLoader {
     schema: Vec<SysctlConfigSchema>,
-}
-
-#[derive(Clone)]
-pub enum SysctlConfigValue {
-    String(String),
-    SysctlConfig(SysctlConfig),
+    options: ParseOptions,
 }
 
 #[derive(Clone)]
@@ -47,34 +50,48 @@ impl SysctlConfigType {
     }
 }
 
-type SysctlConfig = HashMap<String, SysctlConfigValue>;
-
 impl SysctlConfigLoader {
     pub fn new(path: &str) -> Self {
         let file = std::fs::read_to_string(path).unwrap();
         let r = BufReader::new(Cursor::new(file));
         let r = BufReader::new(r);
         let schema = load_sysctl_schema_from_reader(r).unwrap();
-        Self { schema }
+        Self {
+            schema,
+            options: ParseOptions::default(),
+        }
     }
 
-    pub fn load_sysctl(self: &Self, path: &str) -> Result<SysctlConfig> {
-        let file = std::fs::read_to_string(path)?;
-        let r = BufReader::new(Cursor::new(file));
-        let r = BufReader::new(r);
-        let result = self.load_sysctl_from_reader(r)?;
+    /// Parses sysctl files with `options` instead of the defaults, e.g.
+    /// `SysctlConfigLoader::new(path).with_options(ParseOptions::systemd())`.
+    pub fn with_options(mut self, options: ParseOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn load_sysctl(&self, path: &str) -> Result<SysctlConfig> {
+        let result = task1::load_sysctl_with_options(path.to_string(), &self.options)?;
         self.validate(&result)?;
         Ok(result)
     }
 
-    fn validate(self: &Self, m: &SysctlConfig) -> Result<()> {
+    fn validate(&self, m: &SysctlConfig) -> Result<()> {
+        // Glob patterns are keys too, just not ones a schema can declare.
         let mut keys = get_all_keys(m);
+        keys.extend(m.globs().iter().map(|g| join_key(&g.key_path)));
         for schema in self.schema.iter() {
             keys.remove(&schema.key);
         }
         if !keys.is_empty() {
             return Err(Error::msg(format!("surplus keys: {:?}", keys)));
         }
+        if !m.exclusions().is_empty() {
+            let exclusions = m.exclusions().iter().map(|keys| join_key(keys));
+            return Err(Error::msg(format!(
+                "surplus exclusions: {:?}",
+                exclusions.collect::<Vec<String>>()
+            )));
+        }
 
         for schema in self.schema.iter() {
             let mut map = m;
@@ -82,11 +99,11 @@ impl SysctlConfigLoader {
             let key = &schema.key;
             let typ = &schema.typ;
 
-            let keys = key.split('.').collect::<Vec<&str>>();
+            let keys = split_key(key);
             let value = {
                 let mut v = Err(Error::msg(format!("key not found: {}", key)));
                 for i in 0..keys.len() {
-                    let key = keys[i];
+                    let key = keys[i].as_ref();
                     if i == keys.len() - 1 {
                         v = Ok(map.get(key));
                         break;
@@ -164,67 +181,6 @@ impl SysctlConfigLoader {
 
         Ok(())
     }
-
-    fn load_sysctl_from_reader<T: Read>(self: &Self, reader: BufReader<T>) -> Result<SysctlConfig> {
-        let mut map = SysctlConfig::new();
-        for line in reader.lines() {
-            let line = line?;
-            self.insert_entry_of_line(&mut map, line.as_str())?;
-        }
-        Ok(map)
-    }
-
-    fn insert_entry_of_line(self: &Self, map: &mut SysctlConfig, line: &str) -> Result<()> {
-        let mut line = line;
-        if line.is_empty() {
-            return Ok(());
-        }
-
-        if line.starts_with("#") || line.starts_with(";") {
-            return Ok(());
-        }
-
-        let error_or_ignore = if line.starts_with("-") {
-            line = &line[1..];
-            |_| Ok(())
-        } else {
-            |s| Err(Error::msg(s))
-        };
-
-        let parts: Vec<&str> = line.splitn(2, '=').collect();
-        if parts.len() != 2 {
-            return error_or_ignore("invalid line");
-        }
-        let key = parts[0].trim();
-        let value = parts[1].trim();
-        if key.is_empty() || value.is_empty() || key.contains(' ') {
-            return error_or_ignore("invalid line");
-        }
-
-        let keys = key.split('.').collect::<Vec<&str>>();
-
-        let mut m = map;
-        for i in 0..keys.len() {
-            let key = keys[i];
-            if i == keys.len() - 1 {
-                m.insert(
-                    key.to_string(),
-                    SysctlConfigValue::String(value.to_string()),
-                );
-            } else {
-                let next_m = m
-                    .entry(key.to_string())
-                    .or_insert_with(|| SysctlConfigValue::SysctlConfig(SysctlConfig::new()));
-                if let SysctlConfigValue::SysctlConfig(next_m) = next_m {
-                    m = next_m;
-                } else {
-                    return error_or_ignore("invalid line");
-                }
-            }
-        }
-
-        Ok(())
-    }
 }
 
 fn load_sysctl_schema_from_reader<T: Read>(
@@ -259,22 +215,19 @@ fn insert_schema_of_line(schema: &mut Vec<SysctlConfigSchema>, line: String) ->
 
 fn get_all_keys(m: &SysctlConfig) -> HashSet<String> {
     let mut keys = HashSet::new();
-    insert_key(m, "", &mut keys);
+    insert_key(m, &mut vec![], &mut keys);
     keys
 }
 
-fn insert_key(m: &SysctlConfig, prev_key: &str, set: &mut HashSet<String>) -> () {
+fn insert_key<'a>(m: &'a SysctlConfig, prev_keys: &mut Vec<&'a str>, set: &mut HashSet<String>) {
     for (k, v) in m.iter() {
-        let key = if prev_key == "" {
-            k.to_string()
-        } else {
-            format!("{}.{}", prev_key, k)
-        };
+        prev_keys.push(k);
         if let SysctlConfigValue::SysctlConfig(v) = v {
-            insert_key(v, &key, set);
+            insert_key(v, prev_keys, set);
         } else {
-            set.insert(key.to_string());
+            set.insert(join_key(prev_keys));
         }
+        prev_keys.pop();
     }
 }
 
@@ -597,6 +550,59 @@ piyo = false
         assert!(result.is_err());
     }
 
+    #[test]
+    fn ng_surplus_glob() {
+        let test_data_schema = "hoge -> int
+";
+
+        let mut schema_file = NamedTempFile::new().unwrap();
+        schema_file.write_all(test_data_schema.as_bytes()).unwrap();
+        let loader = SysctlConfigLoader::new(schema_file.path().to_str().unwrap());
+
+        for (test_data_value, expected) in [
+            ("hoge = 1\nlog.fil? = x\n", "surplus keys: {\"log.fil?\"}"),
+            ("hoge = 1\n-piyo\n", "surplus exclusions: [\"piyo\"]"),
+        ] {
+            let mut value_file = NamedTempFile::new().unwrap();
+            value_file.write_all(test_data_value.as_bytes()).unwrap();
+
+            let result = loader.load_sysctl(value_file.path().to_str().unwrap());
+            assert_eq!(result.unwrap_err().to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn ok_ignored_line_without_globs() {
+        let test_data_value = "hoge = 1
+- foobar
+";
+
+        let test_data_schema = "hoge -> int
+";
+
+        let mut value_file = NamedTempFile::new().unwrap();
+        value_file.write_all(test_data_value.as_bytes()).unwrap();
+        let mut schema_file = NamedTempFile::new().unwrap();
+        schema_file.write_all(test_data_schema.as_bytes()).unwrap();
+
+        let loader = SysctlConfigLoader::new(schema_file.path().to_str().unwrap());
+        let result = loader.load_sysctl(value_file.path().to_str().unwrap());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "surplus exclusions: [\"foobar\"]"
+        );
+
+        let loader = loader.with_options(ParseOptions::new().globs(false));
+        let map = loader
+            .load_sysctl(value_file.path().to_str().unwrap())
+            .unwrap();
+        assert!(map.exclusions().is_empty());
+        assert_eq!(
+            map.get("hoge"),
+            Some(&SysctlConfigValue::String("1".to_string()))
+        );
+    }
+
     #[test]
     fn ng_surplus_nested() {
         let test_data_value = "hoge.fuga = 1
@@ -620,4 +626,191 @@ piyo -> float
         let result = loader.load_sysctl(value_file.path().to_str().unwrap());
         assert!(result.is_err());
     }
+
+    #[test]
+    fn ng_parse_error() {
+        let test_data_value = "hoge = 1
+piyo
+";
+
+        let test_data_schema = "hoge -> int
+piyo -> string
+";
+
+        let mut value_file = NamedTempFile::new().unwrap();
+        value_file.write_all(test_data_value.as_bytes()).unwrap();
+        let mut schema_file = NamedTempFile::new().unwrap();
+        schema_file.write_all(test_data_schema.as_bytes()).unwrap();
+
+        let loader = SysctlConfigLoader::new(schema_file.path().to_str().unwrap());
+
+        let result = loader.load_sysctl(value_file.path().to_str().unwrap());
+        let err = result.unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::MissingDelimiter);
+        assert_eq!(err.line, 2);
+    }
+
+    #[test]
+    fn ok_slash_separator() {
+        let test_data_value = "net/ipv4/conf/eth0.100/rp_filter = 2
+net.ipv4.ip_forward = 1
+";
+
+        let test_data_schema = "net.ipv4.conf.eth0/100.rp_filter -> int
+net/ipv4/ip_forward -> int
+";
+
+        let mut value_file = NamedTempFile::new().unwrap();
+        value_file.write_all(test_data_value.as_bytes()).unwrap();
+        let mut schema_file = NamedTempFile::new().unwrap();
+        schema_file.write_all(test_data_schema.as_bytes()).unwrap();
+
+        let loader = SysctlConfigLoader::new(schema_file.path().to_str().unwrap());
+
+        let map = loader
+            .load_sysctl(value_file.path().to_str().unwrap())
+            .unwrap();
+        let keys = get_all_keys(&map);
+        assert!(keys.contains("net/ipv4/conf/eth0.100/rp_filter"));
+        assert!(keys.contains("net.ipv4.ip_forward"));
+
+        if let Some(SysctlConfigValue::SysctlConfig(net)) = map.get("net") {
+            if let Some(SysctlConfigValue::SysctlConfig(ipv4)) = net.get("ipv4") {
+                assert_eq!(
+                    ipv4.get("ip_forward"),
+                    Some(&SysctlConfigValue::String("1".to_string()))
+                );
+                if let Some(SysctlConfigValue::SysctlConfig(conf)) = ipv4.get("conf") {
+                    assert!(conf.contains_key("eth0.100"));
+                } else {
+                    panic!("expected SysctlConfigValue::SysctlConfig: key={}", "conf");
+                }
+            } else {
+                panic!("expected SysctlConfigValue::SysctlConfig: key={}", "ipv4");
+            }
+        } else {
+            panic!("expected SysctlConfigValue::SysctlConfig: key={}", "net");
+        }
+    }
+
+    #[test]
+    fn ng_slash_separator() {
+        let test_data_value = "net/ipv4/conf/eth0.100/rp_filter = 2
+net.ipv4.ip_forward = 1
+";
+
+        let test_data_schema = "net.ipv4.conf.eth0/100.rp_filter -> int
+net/ipv4/ip_forward -> bool
+";
+
+        let mut value_file = NamedTempFile::new().unwrap();
+        value_file.write_all(test_data_value.as_bytes()).unwrap();
+        let mut schema_file = NamedTempFile::new().unwrap();
+        schema_file.write_all(test_data_schema.as_bytes()).unwrap();
+
+        let loader = SysctlConfigLoader::new(schema_file.path().to_str().unwrap());
+
+        let result = loader.load_sysctl(value_file.path().to_str().unwrap());
+        let err = result.unwrap_err();
+        assert!(
+            err.to_string().contains("key=net.ipv4.ip_forward"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn ok_with_options() {
+        let test_data_value = "  # managed by hand
+hoge = 1  # tuned
+piyo =
+";
+
+        let test_data_schema = "hoge -> int
+piyo -> string
+";
+
+        let mut value_file = NamedTempFile::new().unwrap();
+        value_file.write_all(test_data_value.as_bytes()).unwrap();
+        let mut schema_file = NamedTempFile::new().unwrap();
+        schema_file.write_all(test_data_schema.as_bytes()).unwrap();
+
+        let loader = SysctlConfigLoader::new(schema_file.path().to_str().unwrap());
+        assert!(loader
+            .load_sysctl(value_file.path().to_str().unwrap())
+            .is_err());
+
+        let loader = loader.with_options(ParseOptions::systemd().inline_comments(true));
+        let map = loader
+            .load_sysctl(value_file.path().to_str().unwrap())
+            .unwrap();
+        if let Some(SysctlConfigValue::String(v)) = map.get("hoge") {
+            assert_eq!(v, "1");
+        } else {
+            panic!("expected SysctlConfigValue::String: key={}", "hoge");
+        }
+    }
+
+    #[test]
+    fn ok_sections() {
+        let test_data_value = "endpoint = https://example.com
+[log]
+file = /var/log/app.log
+level = 3
+";
+
+        let test_data_schema = "endpoint -> string
+log.file -> string
+log.level -> int
+";
+
+        let mut value_file = NamedTempFile::new().unwrap();
+        value_file.write_all(test_data_value.as_bytes()).unwrap();
+        let mut schema_file = NamedTempFile::new().unwrap();
+        schema_file.write_all(test_data_schema.as_bytes()).unwrap();
+
+        let loader = SysctlConfigLoader::new(schema_file.path().to_str().unwrap())
+            .with_options(ParseOptions::new().sections(true));
+        let map = loader
+            .load_sysctl(value_file.path().to_str().unwrap())
+            .unwrap();
+        if let Some(SysctlConfigValue::SysctlConfig(log)) = map.get("log") {
+            assert_eq!(
+                log.get("level"),
+                Some(&SysctlConfigValue::String("3".to_string()))
+            );
+        } else {
+            panic!("expected SysctlConfigValue::SysctlConfig: key={}", "log");
+        }
+    }
+
+    #[test]
+    fn ng_limits() {
+        let test_data_value = format!("hoge = 1\n{} = 1\n", vec!["piyo"; 100_000].join("."));
+
+        let test_data_schema = "hoge -> int
+";
+
+        let mut value_file = NamedTempFile::new().unwrap();
+        value_file.write_all(test_data_value.as_bytes()).unwrap();
+        let mut schema_file = NamedTempFile::new().unwrap();
+        schema_file.write_all(test_data_schema.as_bytes()).unwrap();
+
+        let loader = SysctlConfigLoader::new(schema_file.path().to_str().unwrap());
+        let err = loader
+            .load_sysctl(value_file.path().to_str().unwrap())
+            .unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::KeyTooDeep { limit: 128 });
+        assert_eq!(err.line, 2);
+
+        let loader = loader.with_options(ParseOptions::new().max_line_length(64));
+        let err = loader
+            .load_sysctl(value_file.path().to_str().unwrap())
+            .unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::LineTooLong { limit: 64 });
+        assert_eq!(err.line, 2);
+    }
 }